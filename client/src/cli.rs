@@ -0,0 +1,256 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use clap::{Args, Parser, Subcommand};
+use toml::{Table, Value};
+use uuid::Uuid;
+
+use client::{config::Source, tunnel::Target};
+
+const DEFAULT_CONFIG_PATH: &str = "rkp-client.toml";
+
+/// Tunnels local connections through an rkp server.
+///
+/// Settings come from the config file, the selected profile in it and the
+/// options below, later ones overriding earlier ones. Secrets are never taken
+/// from the command line: set `RKP_PRIVATE_UUID` and `RKP_SOCKS_PASSWORD`, or
+/// point to files with `--private-uuid-file` and `socks.password_file`.
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    /// Config file [default: rkp-client.toml]
+    #[arg(short, long, global = true, env = "RKP_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Profile from the config file to apply
+    #[arg(short, long, global = true, env = "RKP_PROFILE")]
+    pub profile: Option<String>,
+    #[command(flatten)]
+    pub overrides: Overrides,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum Command {
+    /// Log in and serve the local listeners (the default)
+    Connect,
+    /// Show what the server reports in the multiplayer server list
+    Ping,
+    /// Validate the settings and try to log in
+    Check,
+    /// Show which routing rule applies to each target, without connecting
+    Route {
+        #[arg(required = true, value_name = "HOST:PORT")]
+        targets: Vec<Target>,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct Overrides {
+    /// Server to connect to
+    #[arg(short, long, global = true, value_name = "HOST[:PORT]")]
+    pub server: Option<String>,
+    /// Expected fingerprint of the server key
+    #[arg(long, global = true, value_name = "HEX")]
+    pub fingerprint: Option<String>,
+    /// Where fingerprints of unpinned servers are remembered
+    #[arg(long, global = true, value_name = "PATH")]
+    pub known_hosts: Option<PathBuf>,
+    /// Username to log in as
+    #[arg(short, long, global = true)]
+    pub name: Option<String>,
+    /// Public UUID of the user
+    #[arg(long, global = true, value_name = "UUID")]
+    pub public_uuid: Option<Uuid>,
+    /// File containing the private UUID
+    #[arg(long, global = true, value_name = "PATH")]
+    pub private_uuid_file: Option<PathBuf>,
+    /// Serve a SOCKS5 proxy on this address
+    #[arg(long, global = true, value_name = "ADDR")]
+    pub socks: Option<SocketAddr>,
+    /// Serve an HTTP proxy on this address
+    #[arg(long, global = true, value_name = "ADDR")]
+    pub http: Option<SocketAddr>,
+    /// Forward a local TCP port, replacing the forwards from the config
+    #[arg(long, global = true, value_name = "LISTEN=TARGET")]
+    pub forward: Vec<String>,
+    /// Forward a local UDP port, replacing the forwards from the config
+    #[arg(long, global = true, value_name = "LISTEN=TARGET")]
+    pub forward_udp: Vec<String>,
+    /// Forward a server port to a local target, replacing the reverse
+    /// forwards from the config
+    #[arg(long, global = true, value_name = "PORT=TARGET")]
+    pub reverse: Vec<String>,
+}
+
+impl Cli {
+    pub fn source(&self) -> Source {
+        Source {
+            path: self
+                .config
+                .clone()
+                .unwrap_or_else(|| DEFAULT_CONFIG_PATH.into()),
+            required: self.config.is_some(),
+            profile: self.profile.clone(),
+            overrides: self.overrides.to_table(),
+        }
+    }
+}
+
+impl Overrides {
+    /// Lays the options out like the config file, so they can be merged into
+    /// it and go through the same validation.
+    fn to_table(&self) -> Table {
+        let mut settings = Table::new();
+        let mut server = Table::new();
+        let mut user = Table::new();
+
+        if let Some(address) = &self.server {
+            // Without a port, the one from the config file stays.
+            match Target::parse(address, None) {
+                Some(Target::Addr(addr)) => {
+                    server.insert("host".into(), addr.ip().to_string().into());
+                    server.insert("port".into(), i64::from(addr.port()).into());
+                }
+                Some(Target::Domain(host, port)) => {
+                    server.insert("host".into(), host.into());
+                    server.insert("port".into(), i64::from(port).into());
+                }
+                None => {
+                    server.insert("host".into(), address.clone().into());
+                }
+            }
+        }
+
+        if let Some(fingerprint) = &self.fingerprint {
+            server.insert("fingerprint".into(), fingerprint.clone().into());
+        }
+
+        if let Some(name) = &self.name {
+            user.insert("name".into(), name.clone().into());
+        }
+
+        if let Some(uuid) = &self.public_uuid {
+            user.insert("public_uuid".into(), uuid.to_string().into());
+        }
+
+        if let Some(path) = &self.private_uuid_file {
+            user.insert("private_uuid_file".into(), path_value(path));
+        }
+
+        if !server.is_empty() {
+            settings.insert("server".into(), server.into());
+        }
+
+        if !user.is_empty() {
+            settings.insert("user".into(), user.into());
+        }
+
+        if let Some(path) = &self.known_hosts {
+            settings.insert("known_hosts".into(), path_value(path));
+        }
+
+        for (name, listen) in [("socks", self.socks), ("http", self.http)] {
+            if let Some(listen) = listen {
+                let mut table = Table::new();
+                table.insert("listen".into(), listen.to_string().into());
+
+                settings.insert(name.into(), table.into());
+            }
+        }
+
+        if !self.forward.is_empty() || !self.forward_udp.is_empty() {
+            let forwards = self
+                .forward
+                .iter()
+                .map(|rule| (rule, false))
+                .chain(self.forward_udp.iter().map(|rule| (rule, true)))
+                .map(|(rule, udp)| {
+                    let (listen, target) = rule.split_once('=').unwrap_or((rule, ""));
+
+                    let mut table = Table::new();
+                    table.insert("listen".into(), listen.into());
+                    table.insert("target".into(), target.into());
+                    table.insert("udp".into(), udp.into());
+
+                    Value::Table(table)
+                })
+                .collect::<Vec<_>>();
+
+            settings.insert("forward".into(), forwards.into());
+        }
+
+        if !self.reverse.is_empty() {
+            let reverses = self
+                .reverse
+                .iter()
+                .map(|rule| {
+                    let (port, target) = rule.split_once('=').unwrap_or((rule, ""));
+
+                    let mut table = Table::new();
+                    // Left as a string if it isn't a number, so the config
+                    // validation reports it.
+                    table.insert(
+                        "port".into(),
+                        port.parse::<i64>()
+                            .map_or_else(|_| port.into(), Value::from),
+                    );
+                    table.insert("target".into(), target.into());
+
+                    Value::Table(table)
+                })
+                .collect::<Vec<_>>();
+
+            settings.insert("reverse".into(), reverses.into());
+        }
+
+        settings
+    }
+}
+
+fn path_value(path: &Path) -> Value {
+    path.to_string_lossy().into_owned().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server_table(address: &str) -> Table {
+        let cli = Cli::parse_from(["client", "--server", address, "check"]);
+
+        cli.overrides.to_table()["server"]
+            .as_table()
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn server_with_port() {
+        let server = server_table("example.com:25566");
+        assert_eq!(server["host"].as_str(), Some("example.com"));
+        assert_eq!(server["port"].as_integer(), Some(25566));
+
+        let server = server_table("[::1]:25566");
+        assert_eq!(server["host"].as_str(), Some("::1"));
+        assert_eq!(server["port"].as_integer(), Some(25566));
+    }
+
+    #[test]
+    fn bare_ipv6_keeps_config_port() {
+        for address in ["::1", "fe80::1:2", "2001:db8::2556"] {
+            let server = server_table(address);
+            assert_eq!(server["host"].as_str(), Some(address));
+            assert!(!server.contains_key("port"));
+        }
+    }
+
+    #[test]
+    fn host_without_port() {
+        let server = server_table("127.0.0.1");
+        assert_eq!(server["host"].as_str(), Some("127.0.0.1"));
+        assert!(!server.contains_key("port"));
+    }
+}
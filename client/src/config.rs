@@ -0,0 +1,654 @@
+use std::{collections::HashSet, env, fs, io, net::SocketAddr, path::PathBuf, time::Duration};
+
+use anyhow::{Context, Result, bail, ensure};
+use serde::{Deserialize, Deserializer, de::DeserializeOwned};
+use toml::{Table, Value};
+use uuid::Uuid;
+
+use crate::{
+    routing::{Action, Cidr, PortRange},
+    tunnel::Target,
+};
+
+/// Config file key of the named profiles.
+const PROFILES_KEY: &str = "profile";
+
+/// Settings which may also be read from `<key>_file` or, taking precedence,
+/// from an environment variable, so they don't have to sit in the config.
+const SECRETS: [(&str, &str, &str); 2] = [
+    ("user", "private_uuid", "RKP_PRIVATE_UUID"),
+    ("socks", "password", "RKP_SOCKS_PASSWORD"),
+];
+
+/// Where settings come from, later ones overriding earlier ones table by
+/// table: the top level of the config file, the selected profile in it, and
+/// the command line.
+#[derive(Clone, Debug)]
+pub struct Source {
+    pub path: PathBuf,
+    /// Whether a missing config file is an error, rather than meaning that
+    /// everything comes from the command line.
+    pub required: bool,
+    pub profile: Option<String>,
+    pub overrides: Table,
+}
+
+/// Everything the client reads from its config file at startup.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub server: Server,
+    pub user: User,
+    /// Local SOCKS5 proxy, disabled if missing.
+    pub socks: Option<Socks>,
+    /// Local HTTP proxy, disabled if missing.
+    pub http: Option<Http>,
+    /// Accepts connections redirected by iptables, disabled if missing. Only
+    /// available on Linux.
+    pub transparent: Option<Transparent>,
+    /// Routes the packets of a TUN device through the tunnel, disabled if
+    /// missing. Only available on Linux.
+    pub tun: Option<Tun>,
+    /// Local DNS resolver which asks through the tunnel, disabled if missing.
+    pub dns: Option<Dns>,
+    /// Local ports which are forwarded to fixed targets.
+    #[serde(default, rename = "forward")]
+    pub forwards: Vec<Forward>,
+    /// Server ports which are forwarded to local targets.
+    #[serde(default, rename = "reverse")]
+    pub reverses: Vec<Reverse>,
+    #[serde(default)]
+    pub routing: Routing,
+    #[serde(default)]
+    pub reconnect: Reconnect,
+    /// Local HTTP endpoint reporting the connection state as JSON, disabled
+    /// if missing.
+    pub status: Option<Status>,
+    /// Where fingerprints of servers without a pinned one are remembered.
+    #[serde(default = "default_known_hosts")]
+    pub known_hosts: PathBuf,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Server {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Expected fingerprint of the server key, as printed by
+    /// `server fingerprint`. Without it the key is trusted on first use.
+    pub fingerprint: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct User {
+    pub name: String,
+    /// Sent before the connection is encrypted, so it only tells users apart.
+    pub public_uuid: Uuid,
+    /// Sent once the connection is encrypted and proves who the user is.
+    /// Also read from `private_uuid_file` or `RKP_PRIVATE_UUID`.
+    pub private_uuid: Uuid,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Socks {
+    #[serde(default = "default_socks_listen")]
+    pub listen: SocketAddr,
+    /// Clients have to log in with these if set.
+    pub username: Option<String>,
+    /// Also read from `password_file` or `RKP_SOCKS_PASSWORD`.
+    pub password: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Http {
+    #[serde(default = "default_http_listen")]
+    pub listen: SocketAddr,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Transparent {
+    #[serde(default = "default_transparent_listen")]
+    pub listen: SocketAddr,
+    /// Expect connections from the `TPROXY` target instead of `REDIRECT`.
+    /// Needs `CAP_NET_ADMIN`.
+    #[serde(default)]
+    pub tproxy: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tun {
+    /// Name of the device, it is created if it doesn't exist.
+    #[serde(default = "default_tun_name")]
+    pub name: String,
+    /// Has to match the MTU the device is configured with.
+    #[serde(default = "default_tun_mtu")]
+    pub mtu: usize,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Dns {
+    /// Address for both UDP and TCP queries.
+    #[serde(default = "default_dns_listen")]
+    pub listen: SocketAddr,
+    /// Resolver the server sends the queries to.
+    #[serde(default = "default_dns_upstream")]
+    pub upstream: SocketAddr,
+    /// How many answers are cached at most, zero disables the cache.
+    #[serde(default = "default_dns_cache_size")]
+    pub cache_size: usize,
+    /// Answers are cached for their TTL, but no longer than this.
+    #[serde(
+        rename = "max_ttl_secs",
+        default = "default_dns_max_ttl",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub max_ttl: Duration,
+    /// How long the upstream resolver may take to answer.
+    #[serde(
+        rename = "timeout_secs",
+        default = "default_dns_timeout",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub timeout: Duration,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Forward {
+    pub listen: SocketAddr,
+    /// `host:port` to connect to from the server.
+    #[serde(deserialize_with = "deserialize_target")]
+    pub target: Target,
+    #[serde(default)]
+    pub udp: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Reverse {
+    /// Port the server listens on, it has to be in the user's `bind_ports`.
+    pub port: u16,
+    /// `host:port` to connect to from the client.
+    #[serde(deserialize_with = "deserialize_target")]
+    pub target: Target,
+}
+
+/// Which destinations are reached through the tunnel, directly or not at all.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Routing {
+    /// Applies to connections no rule matches.
+    #[serde(default)]
+    pub default: Action,
+    /// Checked in order, the first matching one decides.
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+}
+
+/// Matches a destination if any of its domains, suffixes, CIDRs or list
+/// entries does, and its port is in `ports`. Either part may be left out.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub action: Action,
+    /// Domains matched exactly.
+    #[serde(default)]
+    pub domain: Vec<String>,
+    /// Domains matched together with their subdomains.
+    #[serde(default)]
+    pub domain_suffix: Vec<String>,
+    /// Networks matched by IP destinations. Domains aren't resolved for this.
+    #[serde(default)]
+    pub cidr: Vec<Cidr>,
+    #[serde(default)]
+    pub ports: Vec<PortRange>,
+    /// Files with one IP address, CIDR or domain suffix per line, `=` in
+    /// front of a domain matches it exactly.
+    #[serde(default)]
+    pub lists: Vec<PathBuf>,
+}
+
+/// What happens after the connection to the server is lost.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Reconnect {
+    /// Whether to log in again at all. Otherwise the client exits.
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Delay before the first attempt, doubled after every failed one.
+    #[serde(
+        rename = "initial_delay_ms",
+        default = "default_reconnect_initial_delay",
+        deserialize_with = "deserialize_millis"
+    )]
+    pub initial_delay: Duration,
+    #[serde(
+        rename = "max_delay_secs",
+        default = "default_reconnect_max_delay",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub max_delay: Duration,
+    /// Failed attempts in a row after which the client exits, zero retries
+    /// forever.
+    #[serde(default)]
+    pub max_attempts: u32,
+    /// What happens to new local connections while there is no session.
+    #[serde(default)]
+    pub while_reconnecting: Outage,
+    /// How long queued connections wait for the session to come back.
+    #[serde(
+        rename = "queue_timeout_secs",
+        default = "default_reconnect_queue_timeout",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub queue_timeout: Duration,
+}
+
+impl Default for Reconnect {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            initial_delay: default_reconnect_initial_delay(),
+            max_delay: default_reconnect_max_delay(),
+            max_attempts: 0,
+            while_reconnecting: Outage::default(),
+            queue_timeout: default_reconnect_queue_timeout(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outage {
+    /// Hold connections until the session is back or `queue_timeout_secs`
+    /// passes.
+    #[default]
+    Queue,
+    /// Refuse connections right away.
+    Fail,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Status {
+    #[serde(default = "default_status_listen")]
+    pub listen: SocketAddr,
+}
+
+impl Source {
+    fn load<T: DeserializeOwned>(&self) -> Result<T> {
+        let path = self.path.display();
+        let mut settings = self.read()?;
+
+        resolve_secrets(&mut settings)?;
+
+        Value::Table(settings)
+            .try_into()
+            .with_context(|| format!("failed to parse config {path}"))
+    }
+
+    fn read(&self) -> Result<Table> {
+        let path = self.path.display();
+
+        let mut settings = match fs::read_to_string(&self.path) {
+            Ok(text) => {
+                toml::from_str(&text).with_context(|| format!("failed to parse config {path}"))?
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !self.required => Table::new(),
+            Err(e) => return Err(e).with_context(|| format!("failed to read config {path}")),
+        };
+
+        let mut profiles = match settings.remove(PROFILES_KEY) {
+            Some(Value::Table(profiles)) => profiles,
+            Some(_) => bail!("failed to parse config {path}: `{PROFILES_KEY}` must be a table"),
+            None => Table::new(),
+        };
+
+        if let Some(name) = &self.profile {
+            match profiles.remove(name) {
+                Some(Value::Table(profile)) => merge(&mut settings, profile),
+                Some(_) => {
+                    bail!("failed to parse config {path}: `{PROFILES_KEY}.{name}` must be a table")
+                }
+                None => bail!("profile `{name}` not found in {path}"),
+            }
+        }
+
+        merge(&mut settings, self.overrides.clone());
+
+        Ok(settings)
+    }
+}
+
+impl Config {
+    /// Settings for logging in to `server` as `user`, with every local
+    /// listener disabled and everything else at its default.
+    pub fn new(server: Server, user: User) -> Self {
+        Self {
+            server,
+            user,
+            socks: None,
+            http: None,
+            transparent: None,
+            tun: None,
+            dns: None,
+            forwards: Vec::new(),
+            reverses: Vec::new(),
+            routing: Routing::default(),
+            reconnect: Reconnect::default(),
+            status: None,
+            known_hosts: default_known_hosts(),
+        }
+    }
+
+    pub fn load(source: &Source) -> Result<Self> {
+        let mut config: Self = source.load()?;
+
+        config
+            .validate()
+            .with_context(|| format!("invalid config {}", source.path.display()))?;
+
+        Ok(config)
+    }
+
+    pub(crate) fn validate(&mut self) -> Result<()> {
+        ensure!(
+            !self.server.host.is_empty(),
+            "`server.host`: must not be empty"
+        );
+
+        ensure!(
+            (1..=16).contains(&self.user.name.chars().count()),
+            "`user.name`: must be 1 to 16 characters long"
+        );
+
+        if let Some(fingerprint) = &mut self.server.fingerprint {
+            // Accept the `ab:cd:..` form as well, some tools print it like that.
+            *fingerprint = fingerprint.replace(':', "").to_ascii_lowercase();
+
+            ensure!(
+                fingerprint.len() == 64 && fingerprint.chars().all(|c| c.is_ascii_hexdigit()),
+                "`server.fingerprint`: must be 64 hex digits"
+            );
+        }
+
+        if let Some(socks) = &self.socks {
+            ensure!(
+                socks.username.is_some() == socks.password.is_some(),
+                "`socks.username`: must be set together with `socks.password`"
+            );
+
+            // RFC 1929 sends both with a single length byte.
+            for (key, value) in [("username", &socks.username), ("password", &socks.password)] {
+                ensure!(
+                    value
+                        .as_ref()
+                        .is_none_or(|value| (1..=255).contains(&value.len())),
+                    "`socks.{key}`: must be 1 to 255 bytes long"
+                );
+            }
+        }
+
+        ensure!(
+            cfg!(target_os = "linux") || self.transparent.is_none(),
+            "`transparent`: only supported on Linux"
+        );
+
+        if let Some(tun) = &self.tun {
+            ensure!(cfg!(target_os = "linux"), "`tun`: only supported on Linux");
+            ensure!(
+                (1..16).contains(&tun.name.len()) && !tun.name.contains(['/', '\0']),
+                "`tun.name`: must be 1 to 15 bytes long without `/`"
+            );
+            // IPv6 doesn't work with less.
+            ensure!(
+                (1280..=65535).contains(&tun.mtu),
+                "`tun.mtu`: must be between 1280 and 65535"
+            );
+        }
+
+        if let Some(dns) = &self.dns {
+            ensure!(
+                !dns.timeout.is_zero(),
+                "`dns.timeout_secs`: must be greater than zero"
+            );
+        }
+
+        let mut listens = HashSet::new();
+
+        for (i, forward) in self.forwards.iter().enumerate() {
+            ensure!(
+                listens.insert((forward.listen, forward.udp)),
+                "`forward[{i}].listen`: {} is already forwarded",
+                forward.listen
+            );
+        }
+
+        self.routing.validate()?;
+
+        ensure!(
+            !self.reconnect.initial_delay.is_zero(),
+            "`reconnect.initial_delay_ms`: must be greater than zero"
+        );
+        ensure!(
+            self.reconnect.max_delay >= self.reconnect.initial_delay,
+            "`reconnect.max_delay_secs`: must not be less than `initial_delay_ms`"
+        );
+        ensure!(
+            !self.reconnect.queue_timeout.is_zero(),
+            "`reconnect.queue_timeout_secs`: must be greater than zero"
+        );
+
+        let mut ports = HashSet::new();
+
+        for (i, reverse) in self.reverses.iter().enumerate() {
+            ensure!(reverse.port != 0, "`reverse[{i}].port`: must not be zero");
+            ensure!(
+                ports.insert(reverse.port),
+                "`reverse[{i}].port`: {} is already forwarded",
+                reverse.port
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl Server {
+    /// Loads only the server settings, for commands which don't log in.
+    pub fn load(source: &Source) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct Partial {
+            server: Server,
+        }
+
+        let Partial { server } = source.load()?;
+
+        ensure!(
+            !server.host.is_empty(),
+            "invalid config {}: `server.host`: must not be empty",
+            source.path.display()
+        );
+
+        Ok(server)
+    }
+}
+
+impl Routing {
+    /// Loads only the routing rules, for trying them out without logging in.
+    pub fn load(source: &Source) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct Partial {
+            #[serde(default)]
+            routing: Routing,
+        }
+
+        let Partial { routing } = source.load()?;
+
+        routing
+            .validate()
+            .with_context(|| format!("invalid config {}", source.path.display()))?;
+
+        Ok(routing)
+    }
+
+    fn validate(&self) -> Result<()> {
+        for (i, rule) in self.rules.iter().enumerate() {
+            ensure!(
+                !rule.domain.is_empty()
+                    || !rule.domain_suffix.is_empty()
+                    || !rule.cidr.is_empty()
+                    || !rule.ports.is_empty()
+                    || !rule.lists.is_empty(),
+                "`routing.rule[{i}]`: matches nothing, set `domain`, `domain_suffix`, `cidr`, \
+                 `ports` or `lists`"
+            );
+
+            for (key, domains) in [
+                ("domain", &rule.domain),
+                ("domain_suffix", &rule.domain_suffix),
+            ] {
+                ensure!(
+                    domains.iter().all(|domain| !domain.trim().is_empty()),
+                    "`routing.rule[{i}].{key}`: must not contain empty domains"
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Merges `overlay` into `base`. Tables are merged key by key, everything
+/// else is replaced.
+fn merge(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overlay)) => merge(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn resolve_secrets(settings: &mut Table) -> Result<()> {
+    for (table_key, key, var) in SECRETS {
+        let file_key = format!("{key}_file");
+
+        let Some(Value::Table(table)) = settings.get_mut(table_key) else {
+            continue;
+        };
+
+        let file = table.remove(&file_key);
+
+        if let Ok(value) = env::var(var) {
+            table.insert(key.to_string(), Value::String(value));
+            continue;
+        }
+
+        let Some(file) = file else {
+            continue;
+        };
+
+        let Some(path) = file.as_str() else {
+            bail!("`{table_key}.{file_key}`: must be a path");
+        };
+
+        let value = fs::read_to_string(path)
+            .with_context(|| format!("`{table_key}.{file_key}`: failed to read {path}"))?;
+
+        table.insert(key.to_string(), Value::String(value.trim().to_string()));
+    }
+
+    Ok(())
+}
+
+fn deserialize_target<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Target, D::Error> {
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+fn deserialize_millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_millis)
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_port() -> u16 {
+    25565
+}
+
+fn default_socks_listen() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 1080))
+}
+
+fn default_http_listen() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 8080))
+}
+
+fn default_transparent_listen() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 12345))
+}
+
+fn default_tun_name() -> String {
+    "rkp0".to_string()
+}
+
+fn default_tun_mtu() -> usize {
+    1500
+}
+
+fn default_dns_listen() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 5353))
+}
+
+fn default_dns_upstream() -> SocketAddr {
+    SocketAddr::from(([1, 1, 1, 1], 53))
+}
+
+fn default_dns_cache_size() -> usize {
+    4096
+}
+
+fn default_dns_max_ttl() -> Duration {
+    Duration::from_secs(86400)
+}
+
+fn default_dns_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_reconnect_initial_delay() -> Duration {
+    Duration::from_millis(500)
+}
+
+fn default_reconnect_max_delay() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_reconnect_queue_timeout() -> Duration {
+    Duration::from_secs(15)
+}
+
+fn default_status_listen() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 9180))
+}
+
+fn default_known_hosts() -> PathBuf {
+    PathBuf::from("rkp-known-hosts")
+}
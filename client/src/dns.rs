@@ -0,0 +1,834 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{self, oneshot},
+    task::AbortHandle,
+};
+
+use crate::{
+    config::Dns,
+    tunnel::{StreamWriter, Target, Tunnel, TunnelStream},
+};
+
+const HEADER_LEN: usize = 12;
+const MAX_MESSAGE_SIZE: usize = 65535;
+/// What UDP clients without EDNS accept.
+const MIN_UDP_SIZE: usize = 512;
+
+const FLAG_TC: u8 = 0x02;
+const FLAG_RD: u8 = 0x01;
+const FLAG_CD: u8 = 0x10;
+/// DNSSEC OK, in the TTL field of an OPT record.
+const EDNS_FLAG_DO: u32 = 0x8000;
+const RCODE_NOERROR: u8 = 0;
+const RCODE_NXDOMAIN: u8 = 3;
+
+const TYPE_SOA: u16 = 6;
+const TYPE_OPT: u16 = 41;
+
+/// Answers DNS queries on `config.listen`, over UDP and TCP, by sending them
+/// through the tunnel to `config.upstream`, so that lookups don't leak
+/// outside of it. Answers are cached for as long as their TTLs allow.
+pub async fn serve(config: Dns, tunnel: Tunnel) -> Result<()> {
+    let socket = UdpSocket::bind(config.listen)
+        .await
+        .with_context(|| format!("failed to listen on UDP {}", config.listen))?;
+    let listener = TcpListener::bind(config.listen)
+        .await
+        .with_context(|| format!("failed to listen on TCP {}", config.listen))?;
+
+    log::info!(
+        "DNS resolver listening on {}, forwarding to {}",
+        config.listen,
+        config.upstream
+    );
+
+    let resolver = Arc::new(Resolver {
+        tunnel,
+        upstream: config.upstream,
+        timeout: config.timeout,
+        max_ttl: config.max_ttl,
+        cache: Mutex::new(Cache::new(config.cache_size)),
+        udp: Mutex::new(None),
+    });
+
+    tokio::try_join!(
+        serve_udp(Arc::new(socket), resolver.clone()),
+        serve_tcp(listener, resolver)
+    )?;
+
+    Ok(())
+}
+
+async fn serve_udp(socket: Arc<UdpSocket>, resolver: Arc<Resolver>) -> Result<()> {
+    let mut buf = vec![0; MAX_MESSAGE_SIZE];
+
+    loop {
+        let (len, peer_addr) = socket.recv_from(&mut buf).await?;
+        let query = buf[..len].to_vec();
+        let socket = socket.clone();
+        let resolver = resolver.clone();
+
+        tokio::spawn(async move {
+            match resolver.resolve(&query, false).await {
+                Ok(reply) => {
+                    if let Err(e) = socket.send_to(&reply, peer_addr).await {
+                        log::debug!("Failed to send DNS reply to {peer_addr}: {e}");
+                    }
+                }
+                Err(e) => log::debug!("DNS query from {peer_addr} failed: {e:#}"),
+            }
+        });
+    }
+}
+
+async fn serve_tcp(listener: TcpListener, resolver: Arc<Resolver>) -> Result<()> {
+    loop {
+        let (mut stream, peer_addr) = listener.accept().await?;
+        let resolver = resolver.clone();
+
+        tokio::spawn(async move {
+            // Queries on one connection are answered in order.
+            loop {
+                let query = match read_framed(&mut stream).await {
+                    Ok(Some(query)) => query,
+                    Ok(None) => return,
+                    Err(e) => {
+                        log::debug!("DNS connection from {peer_addr} failed: {e}");
+                        return;
+                    }
+                };
+
+                let res = match resolver.resolve(&query, true).await {
+                    Ok(reply) => write_framed(&mut stream, &reply).await,
+                    Err(e) => Err(e),
+                };
+
+                if let Err(e) = res {
+                    log::debug!("DNS query from {peer_addr} failed: {e:#}");
+                    return;
+                }
+            }
+        });
+    }
+}
+
+struct Resolver {
+    tunnel: Tunnel,
+    upstream: SocketAddr,
+    timeout: Duration,
+    max_ttl: Duration,
+    cache: Mutex<Cache>,
+    /// Shared by all UDP queries, replaced once it fails.
+    udp: Mutex<Option<Arc<UdpUpstream>>>,
+}
+
+impl Resolver {
+    /// Answers `query` from the cache or the upstream resolver. Replies which
+    /// were truncated for UDP are asked again over TCP for TCP clients.
+    async fn resolve(&self, query: &[u8], tcp: bool) -> Result<Vec<u8>> {
+        if query.len() < HEADER_LEN {
+            bail!("query is too short");
+        }
+
+        let key = cache_key(query);
+        let max_size = if tcp {
+            MAX_MESSAGE_SIZE
+        } else {
+            max_udp_size(query)
+        };
+
+        if let Some(key) = &key
+            && let Some(reply) = self.cache.lock().unwrap().get(key, query, max_size)
+        {
+            return Ok(reply);
+        }
+
+        let mut reply = tokio::time::timeout(self.timeout, self.exchange_udp(query))
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("upstream resolver timed out")))?;
+
+        if tcp && reply[2] & FLAG_TC != 0 {
+            reply = tokio::time::timeout(self.timeout, self.exchange_tcp(query))
+                .await
+                .unwrap_or_else(|_| Err(anyhow!("upstream resolver timed out")))?;
+        }
+
+        if let Some(key) = key
+            && let Some(ttl) = cacheable_ttl(&reply)
+        {
+            let ttl = Duration::from_secs(ttl.into()).min(self.max_ttl);
+            self.cache.lock().unwrap().insert(key, reply.clone(), ttl);
+        }
+
+        Ok(reply)
+    }
+
+    async fn exchange_udp(&self, query: &[u8]) -> Result<Vec<u8>> {
+        let current = self.udp.lock().unwrap().clone();
+
+        let upstream = match current {
+            Some(upstream) if !upstream.is_closed() => upstream,
+            _ => {
+                // Opened without holding the lock, as it waits while the
+                // tunnel reconnects. Of queries racing here, the first one
+                // to finish sets the connection for the others.
+                let stream = self.tunnel.open(Target::Addr(self.upstream), true).await?;
+                let opened = UdpUpstream::spawn(stream);
+
+                let mut udp = self.udp.lock().unwrap();
+
+                match &*udp {
+                    Some(upstream) if !upstream.is_closed() => upstream.clone(),
+                    _ => {
+                        *udp = Some(opened.clone());
+                        opened
+                    }
+                }
+            }
+        };
+
+        upstream.exchange(query).await
+    }
+
+    async fn exchange_tcp(&self, query: &[u8]) -> Result<Vec<u8>> {
+        let stream = self.tunnel.open(Target::Addr(self.upstream), false).await?;
+        let (mut reader, mut writer) = stream.into_split();
+
+        let mut framed = Vec::with_capacity(2 + query.len());
+        framed.extend_from_slice(&(query.len() as u16).to_be_bytes());
+        framed.extend_from_slice(query);
+        writer.send(framed.into()).await?;
+
+        let mut buf = Vec::new();
+
+        loop {
+            if let Some(len) = buf.get(..2).map(|len| u16::from_be_bytes([len[0], len[1]]))
+                && buf.len() >= 2 + usize::from(len)
+            {
+                let reply = buf[2..2 + usize::from(len)].to_vec();
+                check_reply(query, &reply)?;
+
+                return Ok(reply);
+            }
+
+            match reader.recv().await? {
+                Some(data) => buf.extend_from_slice(&data),
+                None => bail!("upstream resolver closed the connection"),
+            }
+        }
+    }
+}
+
+/// A UDP connection to the upstream resolver. Queries get ids of their own
+/// while they are on the way, since different clients may pick the same one.
+struct UdpUpstream {
+    writer: sync::Mutex<StreamWriter>,
+    pending: Arc<Mutex<Pending>>,
+    reader: AbortHandle,
+}
+
+#[derive(Default)]
+struct Pending {
+    closed: bool,
+    next_id: u16,
+    queries: HashMap<u16, oneshot::Sender<Bytes>>,
+}
+
+impl UdpUpstream {
+    fn spawn(stream: TunnelStream) -> Arc<Self> {
+        let (mut reader, writer) = stream.into_split();
+        let pending = Arc::new(Mutex::new(Pending::default()));
+
+        let task = tokio::spawn({
+            let pending = pending.clone();
+
+            async move {
+                while let Ok(Some(reply)) = reader.recv().await {
+                    if reply.len() < HEADER_LEN {
+                        continue;
+                    }
+
+                    let id = u16::from_be_bytes([reply[0], reply[1]]);
+
+                    if let Some(tx) = pending.lock().unwrap().queries.remove(&id) {
+                        let _ = tx.send(reply);
+                    }
+                }
+
+                // Fails the queries which are still waiting.
+                let mut pending = pending.lock().unwrap();
+                pending.closed = true;
+                pending.queries.clear();
+            }
+        });
+
+        Arc::new(Self {
+            writer: sync::Mutex::new(writer),
+            pending,
+            reader: task.abort_handle(),
+        })
+    }
+
+    fn is_closed(&self) -> bool {
+        self.pending.lock().unwrap().closed
+    }
+
+    async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>> {
+        let (tx, rx) = oneshot::channel();
+
+        let id = {
+            let mut pending = self.pending.lock().unwrap();
+
+            if pending.closed {
+                bail!("connection to the upstream resolver is closed");
+            }
+
+            while pending.queries.contains_key(&pending.next_id) {
+                pending.next_id = pending.next_id.wrapping_add(1);
+            }
+
+            let id = pending.next_id;
+            pending.next_id = id.wrapping_add(1);
+            pending.queries.insert(id, tx);
+            id
+        };
+
+        // Forgets the query if the answer doesn't come in time.
+        let _guard = RemoveOnDrop(&self.pending, id);
+
+        let mut rewritten = query.to_vec();
+        rewritten[..2].copy_from_slice(&id.to_be_bytes());
+        self.writer.lock().await.send(rewritten.into()).await?;
+
+        let reply = rx
+            .await
+            .map_err(|_| anyhow!("connection to the upstream resolver is closed"))?;
+
+        let mut reply = reply.to_vec();
+        reply[..2].copy_from_slice(&query[..2]);
+        check_reply(query, &reply)?;
+
+        Ok(reply)
+    }
+}
+
+impl Drop for UdpUpstream {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+struct RemoveOnDrop<'a>(&'a Mutex<Pending>, u16);
+
+impl Drop for RemoveOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().queries.remove(&self.1);
+    }
+}
+
+/// Replies keyed by their question, with the time they expire at.
+struct Cache {
+    capacity: usize,
+    entries: HashMap<Vec<u8>, Entry>,
+}
+
+struct Entry {
+    reply: Vec<u8>,
+    stored: Instant,
+    expires: Instant,
+}
+
+impl Cache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Returns the cached reply to `query` with its id and question, and the
+    /// TTLs lowered by the time it spent in the cache.
+    fn get(&mut self, key: &[u8], query: &[u8], max_size: usize) -> Option<Vec<u8>> {
+        let now = Instant::now();
+        let entry = self.entries.get(key)?;
+
+        if entry.expires <= now {
+            self.entries.remove(key);
+            return None;
+        }
+
+        // Answered over TCP, too large for this client.
+        if entry.reply.len() > max_size {
+            return None;
+        }
+
+        let mut reply = entry.reply.clone();
+        let elapsed = now.duration_since(entry.stored).as_secs() as u32;
+
+        reply[..2].copy_from_slice(&query[..2]);
+
+        // Keeps the case of the name as the client sent it.
+        if let Some(asked) = question(query)
+            && question(&reply) == Some(asked.clone())
+        {
+            reply[asked.clone()].copy_from_slice(&query[asked]);
+        }
+
+        for record in records(&reply)? {
+            if record.rtype != TYPE_OPT {
+                let ttl = record.ttl.saturating_sub(elapsed);
+                reply[record.ttl_offset..record.ttl_offset + 4].copy_from_slice(&ttl.to_be_bytes());
+            }
+        }
+
+        Some(reply)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, reply: Vec<u8>, ttl: Duration) {
+        if self.capacity == 0 || ttl.is_zero() {
+            return;
+        }
+
+        let now = Instant::now();
+
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            self.entries.retain(|_, entry| entry.expires > now);
+        }
+
+        // Still full, so the entry expiring first makes room.
+        if self.entries.len() >= self.capacity
+            && let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(key, _)| key.clone())
+        {
+            self.entries.remove(&oldest);
+        }
+
+        self.entries.insert(
+            key,
+            Entry {
+                reply,
+                stored: now,
+                expires: now + ttl,
+            },
+        );
+    }
+}
+
+/// A resource record, as far as caching is concerned.
+struct Record {
+    rtype: u16,
+    ttl: u32,
+    ttl_offset: usize,
+    rdata: Range<usize>,
+}
+
+/// The flags which change the answer and the question with the name in lower
+/// case, or `None` for anything but a plain query with one question. DNSSEC
+/// records are only sent to clients which set the DO bit, so it is part of
+/// the key too.
+fn cache_key(query: &[u8]) -> Option<Vec<u8>> {
+    let question = question(query)?;
+    let opcode = (query[2] >> 3) & 0x0f;
+
+    if opcode != 0 || query[2] & 0x80 != 0 {
+        return None;
+    }
+
+    let dnssec_ok = opt_record(query).is_some_and(|opt| opt.ttl & EDNS_FLAG_DO != 0);
+
+    let mut key = vec![query[2] & FLAG_RD, query[3] & FLAG_CD, u8::from(dnssec_ok)];
+    key.extend(query[question].iter().map(u8::to_ascii_lowercase));
+
+    Some(key)
+}
+
+/// The range of the only question in `message`.
+fn question(message: &[u8]) -> Option<Range<usize>> {
+    if message.len() < HEADER_LEN || u16::from_be_bytes([message[4], message[5]]) != 1 {
+        return None;
+    }
+
+    let end = skip_name(message, HEADER_LEN)? + 4;
+
+    (end <= message.len()).then_some(HEADER_LEN..end)
+}
+
+/// How long a reply may be cached: the lowest TTL in it, or for negative
+/// answers what the SOA record allows. `None` if it shouldn't be cached.
+fn cacheable_ttl(reply: &[u8]) -> Option<u32> {
+    let rcode = reply[3] & 0x0f;
+
+    if reply[2] & FLAG_TC != 0 || (rcode != RCODE_NOERROR && rcode != RCODE_NXDOMAIN) {
+        return None;
+    }
+
+    let answers = u16::from_be_bytes([reply[6], reply[7]]);
+    let records = records(reply)?;
+
+    if answers == 0 {
+        let soa = records.iter().find(|record| record.rtype == TYPE_SOA)?;
+        let minimum = reply.get(soa.rdata.end.checked_sub(4)?..soa.rdata.end)?;
+
+        return Some(soa.ttl.min(u32::from_be_bytes(minimum.try_into().ok()?)));
+    }
+
+    records
+        .iter()
+        .filter(|record| record.rtype != TYPE_OPT)
+        .map(|record| record.ttl)
+        .min()
+}
+
+/// The UDP payload size the client advertises with EDNS.
+fn max_udp_size(query: &[u8]) -> usize {
+    opt_record(query)
+        .and_then(|opt| {
+            // The class of an OPT record is the payload size.
+            let class = query.get(opt.ttl_offset - 2..opt.ttl_offset)?;
+
+            Some(usize::from(u16::from_be_bytes([class[0], class[1]])))
+        })
+        .unwrap_or(MIN_UDP_SIZE)
+        .max(MIN_UDP_SIZE)
+}
+
+/// The OPT record of a message with EDNS.
+fn opt_record(message: &[u8]) -> Option<Record> {
+    records(message)?
+        .into_iter()
+        .find(|record| record.rtype == TYPE_OPT)
+}
+
+/// All records after the question section.
+fn records(message: &[u8]) -> Option<Vec<Record>> {
+    if message.len() < HEADER_LEN {
+        return None;
+    }
+
+    let count = |i: usize| usize::from(u16::from_be_bytes([message[i], message[i + 1]]));
+    let mut offset = HEADER_LEN;
+
+    for _ in 0..count(4) {
+        offset = skip_name(message, offset)? + 4;
+    }
+
+    let mut records = Vec::new();
+
+    for _ in 0..count(6) + count(8) + count(10) {
+        let fixed = skip_name(message, offset)?;
+        let header = message.get(fixed..fixed + 10)?;
+        let rdata_len = usize::from(u16::from_be_bytes([header[8], header[9]]));
+        let rdata = fixed + 10..fixed + 10 + rdata_len;
+
+        if rdata.end > message.len() {
+            return None;
+        }
+
+        records.push(Record {
+            rtype: u16::from_be_bytes([header[0], header[1]]),
+            ttl: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+            ttl_offset: fixed + 4,
+            rdata: rdata.clone(),
+        });
+
+        offset = rdata.end;
+    }
+
+    Some(records)
+}
+
+/// Returns the offset after the name at `offset`.
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(offset)?;
+
+        match len {
+            0 => return Some(offset + 1),
+            // A compression pointer ends the name.
+            len if len & 0xc0 == 0xc0 => return Some(offset + 2),
+            len if len & 0xc0 == 0 => offset += 1 + usize::from(len),
+            _ => return None,
+        }
+    }
+}
+
+/// Makes sure `reply` answers `query` and can be looked at safely.
+fn check_reply(query: &[u8], reply: &[u8]) -> Result<()> {
+    if reply.len() < HEADER_LEN || reply[2] & 0x80 == 0 {
+        bail!("upstream resolver sent a malformed reply");
+    }
+
+    if let (Some(asked), Some(answered)) = (question(query), question(reply))
+        && !query[asked].eq_ignore_ascii_case(&reply[answered])
+    {
+        bail!("upstream resolver answered a different question");
+    }
+
+    Ok(())
+}
+
+async fn read_framed(stream: &mut TcpStream) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 2];
+
+    match stream.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut message = vec![0; usize::from(u16::from_be_bytes(len))];
+    stream.read_exact(&mut message).await?;
+
+    if message.len() < HEADER_LEN {
+        bail!("query is too short");
+    }
+
+    Ok(Some(message))
+}
+
+async fn write_framed(stream: &mut TcpStream, message: &[u8]) -> Result<()> {
+    let mut framed = Vec::with_capacity(2 + message.len());
+    framed.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    framed.extend_from_slice(message);
+
+    stream.write_all(&framed).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TYPE_A: u16 = 1;
+    /// A compression pointer to the name of the question.
+    const QUESTION_NAME: [u8; 2] = [0xc0, 0x0c];
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut encoded = Vec::new();
+
+        for label in name.split('.') {
+            encoded.push(label.len() as u8);
+            encoded.extend_from_slice(label.as_bytes());
+        }
+
+        encoded.push(0);
+        encoded
+    }
+
+    fn record(name: &[u8], rtype: u16, class: u16, ttl: u32, rdata: &[u8]) -> Vec<u8> {
+        let mut record = name.to_vec();
+        record.extend_from_slice(&rtype.to_be_bytes());
+        record.extend_from_slice(&class.to_be_bytes());
+        record.extend_from_slice(&ttl.to_be_bytes());
+        record.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        record.extend_from_slice(rdata);
+        record
+    }
+
+    fn opt(flags: u32) -> Vec<u8> {
+        record(&[0], TYPE_OPT, 4096, flags, &[])
+    }
+
+    fn soa(ttl: u32, minimum: u32) -> Vec<u8> {
+        let mut rdata = name("ns.example.com");
+        rdata.extend(name("hostmaster.example.com"));
+        for value in [1, 7200, 900, 1209600, minimum] {
+            rdata.extend_from_slice(&u32::to_be_bytes(value));
+        }
+
+        record(&QUESTION_NAME, TYPE_SOA, 1, ttl, &rdata)
+    }
+
+    fn message(id: u16, flags: [u8; 2], domain: &str, sections: [&[Vec<u8>]; 3]) -> Vec<u8> {
+        let mut message = id.to_be_bytes().to_vec();
+        message.extend_from_slice(&flags);
+        message.extend_from_slice(&1u16.to_be_bytes());
+
+        for section in sections {
+            message.extend_from_slice(&(section.len() as u16).to_be_bytes());
+        }
+
+        message.extend(name(domain));
+        message.extend_from_slice(&TYPE_A.to_be_bytes());
+        message.extend_from_slice(&1u16.to_be_bytes());
+
+        for record in sections.into_iter().flatten() {
+            message.extend_from_slice(record);
+        }
+
+        message
+    }
+
+    fn query(id: u16, domain: &str) -> Vec<u8> {
+        message(id, [0x01, 0x00], domain, [&[], &[], &[opt(0)]])
+    }
+
+    fn reply(rcode: u8, sections: [&[Vec<u8>]; 3]) -> Vec<u8> {
+        message(0x1234, [0x81, 0x80 | rcode], "example.com", sections)
+    }
+
+    fn a(ttl: u32, ip: [u8; 4]) -> Vec<u8> {
+        record(&QUESTION_NAME, TYPE_A, 1, ttl, &ip)
+    }
+
+    fn ttls(message: &[u8]) -> Vec<u32> {
+        records(message)
+            .unwrap()
+            .iter()
+            .map(|record| record.ttl)
+            .collect()
+    }
+
+    #[test]
+    fn records_after_question() {
+        let reply = reply(
+            RCODE_NOERROR,
+            [
+                &[a(300, [192, 0, 2, 1]), a(60, [192, 0, 2, 2])],
+                &[],
+                &[opt(0)],
+            ],
+        );
+        let records = records(&reply).unwrap();
+
+        assert_eq!(records.len(), 3);
+        assert_eq!(
+            records
+                .iter()
+                .map(|record| record.rtype)
+                .collect::<Vec<_>>(),
+            [TYPE_A, TYPE_A, TYPE_OPT]
+        );
+        assert_eq!(ttls(&reply), [300, 60, 0]);
+
+        for record in &records {
+            let ttl = &reply[record.ttl_offset..record.ttl_offset + 4];
+            assert_eq!(u32::from_be_bytes(ttl.try_into().unwrap()), record.ttl);
+        }
+
+        assert_eq!(reply[records[0].rdata.clone()], [192, 0, 2, 1]);
+        assert_eq!(reply[records[1].rdata.clone()], [192, 0, 2, 2]);
+        assert!(records[2].rdata.is_empty());
+    }
+
+    #[test]
+    fn malformed_records() {
+        let reply = reply(RCODE_NOERROR, [&[a(300, [192, 0, 2, 1])], &[], &[]]);
+
+        assert!(records(&reply[..reply.len() - 1]).is_none());
+        assert!(records(&reply[..HEADER_LEN - 1]).is_none());
+
+        // A label length with the reserved high bits.
+        let mut bad_label = reply.clone();
+        bad_label[HEADER_LEN] = 0x80;
+        assert!(records(&bad_label).is_none());
+    }
+
+    #[test]
+    fn ttl_of_answers() {
+        let reply = reply(
+            RCODE_NOERROR,
+            [
+                &[a(300, [192, 0, 2, 1]), a(60, [192, 0, 2, 2])],
+                &[],
+                &[opt(0)],
+            ],
+        );
+        // The OPT record has no TTL, its field holds flags.
+        assert_eq!(cacheable_ttl(&reply), Some(60));
+    }
+
+    #[test]
+    fn ttl_of_negative_answers() {
+        let nxdomain = reply(RCODE_NXDOMAIN, [&[], &[soa(3600, 300)], &[]]);
+        assert_eq!(cacheable_ttl(&nxdomain), Some(300));
+
+        let nodata = reply(RCODE_NOERROR, [&[], &[soa(100, 300)], &[]]);
+        assert_eq!(cacheable_ttl(&nodata), Some(100));
+
+        let without_soa = reply(RCODE_NXDOMAIN, [&[], &[], &[]]);
+        assert_eq!(cacheable_ttl(&without_soa), None);
+    }
+
+    #[test]
+    fn uncacheable_replies() {
+        let servfail = reply(2, [&[], &[], &[]]);
+        assert_eq!(cacheable_ttl(&servfail), None);
+
+        let mut truncated = reply(RCODE_NOERROR, [&[a(300, [192, 0, 2, 1])], &[], &[]]);
+        truncated[2] |= FLAG_TC;
+        assert_eq!(cacheable_ttl(&truncated), None);
+    }
+
+    #[test]
+    fn cached_reply_is_rewritten() {
+        let query = query(0xabcd, "Example.COM");
+        let key = cache_key(&query).unwrap();
+        let reply = reply(
+            RCODE_NOERROR,
+            [
+                &[a(300, [192, 0, 2, 1]), a(5, [192, 0, 2, 2])],
+                &[],
+                &[opt(0x8000)],
+            ],
+        );
+
+        let mut cache = Cache::new(16);
+        cache.insert(key.clone(), reply.clone(), Duration::from_secs(60));
+        cache.entries.get_mut(&key).unwrap().stored -= Duration::from_secs(10);
+
+        let cached = cache.get(&key, &query, MAX_MESSAGE_SIZE).unwrap();
+
+        assert_eq!(cached[..2], query[..2]);
+        assert_eq!(cached[2..4], reply[2..4]);
+        assert_eq!(
+            cached[question(&query).unwrap()],
+            query[question(&query).unwrap()]
+        );
+        // Lowered by the time spent in the cache, but not below zero, and
+        // the OPT flags stay as they are.
+        assert_eq!(ttls(&cached), [290, 0, 0x8000]);
+    }
+
+    #[test]
+    fn cache_misses() {
+        let query = query(1, "example.com");
+        let key = cache_key(&query).unwrap();
+        let reply = reply(RCODE_NOERROR, [&[a(300, [192, 0, 2, 1])], &[], &[]]);
+
+        let mut cache = Cache::new(16);
+        cache.insert(key.clone(), reply.clone(), Duration::from_secs(60));
+
+        assert!(cache.get(&key, &query, MAX_MESSAGE_SIZE).is_some());
+        assert!(cache.get(&key, &query, reply.len() - 1).is_none());
+
+        cache.entries.get_mut(&key).unwrap().expires = Instant::now();
+        assert!(cache.get(&key, &query, MAX_MESSAGE_SIZE).is_none());
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn dnssec_ok_is_part_of_the_key() {
+        let plain = cache_key(&query(1, "example.com")).unwrap();
+        let without_edns = message(2, [0x01, 0x00], "EXAMPLE.com", [&[], &[], &[]]);
+        let dnssec = message(3, [0x01, 0x00], "example.com", [&[], &[], &[opt(0x8000)]]);
+
+        assert_eq!(cache_key(&without_edns).unwrap(), plain);
+        assert_ne!(cache_key(&dnssec).unwrap(), plain);
+    }
+}
@@ -0,0 +1,104 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use anyhow::Result;
+use bytes::Bytes;
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    sync::mpsc,
+    task::JoinSet,
+};
+
+use crate::{
+    config::Forward,
+    tunnel::{self, Tunnel},
+};
+
+const UDP_BUF_SIZE: usize = 65535;
+/// How many datagrams from one local peer may wait for its tunnel connection
+/// before further ones are dropped.
+const UDP_QUEUE_SIZE: usize = 64;
+
+/// Listens on `forward.listen` and opens a tunnel connection to
+/// `forward.target` for every local connection, or every local UDP peer.
+pub async fn serve(forward: Forward, tunnel: Tunnel) -> Result<()> {
+    if forward.udp {
+        serve_udp(forward, tunnel).await
+    } else {
+        serve_tcp(forward, tunnel).await
+    }
+}
+
+async fn serve_tcp(forward: Forward, tunnel: Tunnel) -> Result<()> {
+    let listener = TcpListener::bind(forward.listen).await?;
+
+    log::info!("Forwarding {} to {}", forward.listen, forward.target);
+
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        let target = forward.target.clone();
+        let tunnel = tunnel.clone();
+
+        tokio::spawn(async move {
+            stream.set_nodelay(true)?;
+
+            let tunnel_stream = match tunnel.open(target.clone(), false).await {
+                Ok(tunnel_stream) => tunnel_stream,
+                Err(e) => {
+                    log::debug!("Forward from {peer_addr} to {target} failed: {e}");
+                    return Ok(());
+                }
+            };
+
+            if let Err(e) = tunnel::relay(stream, tunnel_stream).await {
+                log::debug!("Forward from {peer_addr} to {target} failed: {e}");
+            }
+
+            anyhow::Ok(())
+        });
+    }
+}
+
+async fn serve_udp(forward: Forward, tunnel: Tunnel) -> Result<()> {
+    let socket = Arc::new(UdpSocket::bind(forward.listen).await?);
+
+    log::info!("Forwarding UDP {} to {}", forward.listen, forward.target);
+
+    let mut peers: HashMap<SocketAddr, mpsc::Sender<Bytes>> = HashMap::new();
+    let mut tasks = JoinSet::new();
+    let mut buf = vec![0; UDP_BUF_SIZE];
+
+    loop {
+        tokio::select! {
+            res = socket.recv_from(&mut buf) => {
+                let (len, peer_addr) = res?;
+                let datagram = Bytes::copy_from_slice(&buf[..len]);
+
+                if let Some(tx) = peers.get(&peer_addr)
+                    && !tx.is_closed()
+                {
+                    let _ = tx.try_send(datagram);
+                    continue;
+                }
+
+                let (tx, rx) = mpsc::channel(UDP_QUEUE_SIZE);
+                let _ = tx.try_send(datagram);
+
+                let target = forward.target.clone();
+                let socket = socket.clone();
+                let tunnel = tunnel.clone();
+
+                tasks.spawn(async move {
+                    match tunnel.open(target.clone(), true).await {
+                        Ok(stream) => tunnel::relay_udp(stream, rx, &socket, peer_addr, &[]).await,
+                        Err(e) => log::debug!("UDP forward from {peer_addr} to {target} failed: {e}"),
+                    }
+                });
+
+                peers.insert(peer_addr, tx);
+            }
+            Some(_) = tasks.join_next() => {
+                peers.retain(|_, tx| !tx.is_closed());
+            }
+        }
+    }
+}
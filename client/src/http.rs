@@ -0,0 +1,266 @@
+use anyhow::{Context, Result, bail};
+use bytes::Bytes;
+use protocol::clientbound::transfer::data::ConnectFailReason;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    config::Http,
+    tunnel::{self, ConnectError, Target, Tunnel},
+};
+
+/// Requests with a longer head than this are refused.
+const MAX_HEAD_SIZE: usize = 16384;
+
+/// Hop-by-hop headers, which are meant for the proxy and must not be
+/// forwarded. `Transfer-Encoding` stays, as the body is passed on unchanged.
+const HOP_BY_HOP_HEADERS: [&str; 7] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "upgrade",
+];
+
+/// Accepts HTTP/1.1 proxy clients on `config.listen`. `CONNECT` requests get a
+/// plain tunnel connection, requests with an absolute `http://` URI are
+/// forwarded to their origin server, one per local connection.
+pub async fn serve(config: Http, tunnel: Tunnel) -> Result<()> {
+    let listener = TcpListener::bind(config.listen).await?;
+
+    log::info!("HTTP proxy listening on {}", config.listen);
+
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        let tunnel = tunnel.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &tunnel).await {
+                log::debug!("HTTP proxy connection from {peer_addr} failed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream, tunnel: &Tunnel) -> Result<()> {
+    stream.set_nodelay(true)?;
+
+    let Some((head, rest)) = read_head(&mut stream).await? else {
+        return respond(&mut stream, "431 Request Header Fields Too Large").await;
+    };
+
+    let Ok(head) = String::from_utf8(head) else {
+        return respond(&mut stream, "400 Bad Request").await;
+    };
+
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+
+    let Some((method, uri, version)) = parse_request_line(request_line) else {
+        return respond(&mut stream, "400 Bad Request").await;
+    };
+
+    if method == "CONNECT" {
+        let Some(target) = Target::parse(uri, None) else {
+            return respond(&mut stream, "400 Bad Request").await;
+        };
+
+        log::debug!("HTTP CONNECT to {target}");
+
+        let mut tunnel_stream = match tunnel.open(target, false).await {
+            Ok(tunnel_stream) => tunnel_stream,
+            Err(e) => {
+                respond(&mut stream, error_status(&e)).await?;
+                return Err(e.into());
+            }
+        };
+
+        stream
+            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+            .await?;
+
+        if !rest.is_empty() {
+            tunnel_stream.send(rest.into()).await?;
+        }
+
+        return tunnel::relay(stream, tunnel_stream).await;
+    }
+
+    let Some((authority, path)) =
+        strip_scheme(uri).map(|uri| uri.split_at(uri.find('/').unwrap_or(uri.len())))
+    else {
+        return respond(&mut stream, "400 Bad Request").await;
+    };
+
+    let Some(target) = Target::parse(authority, Some(80)) else {
+        return respond(&mut stream, "400 Bad Request").await;
+    };
+
+    log::debug!("HTTP {method} to {target}");
+
+    let mut tunnel_stream = match tunnel.open(target, false).await {
+        Ok(tunnel_stream) => tunnel_stream,
+        Err(e) => {
+            respond(&mut stream, error_status(&e)).await?;
+            return Err(e.into());
+        }
+    };
+
+    // Origin servers expect the path only. Every request gets its own
+    // connection, so keep-alive is turned off. The origin server then closes
+    // the connection after its response, and the local one is closed with
+    // it, so that a keep-alive client sends its next request through a new
+    // one where it is routed again.
+    let path = if path.is_empty() { "/" } else { path };
+    let mut request = format!("{method} {path} {version}\r\n");
+    request.push_str(&end_to_end_headers(lines));
+    request.push_str("Connection: close\r\n\r\n");
+
+    let mut request = request.into_bytes();
+    request.extend_from_slice(&rest);
+
+    tunnel_stream.send(Bytes::from(request)).await?;
+
+    tunnel::relay_until_remote_done(stream, tunnel_stream).await
+}
+
+/// Reads up to the empty line after the headers. Returns the head and the
+/// bytes which were read past it, or `None` if the head is too large.
+async fn read_head(stream: &mut TcpStream) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let mut buf = Vec::new();
+
+    loop {
+        if let Some(end) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+            let rest = buf.split_off(end + 4);
+            buf.truncate(end);
+
+            return Ok(Some((buf, rest)));
+        }
+
+        if buf.len() > MAX_HEAD_SIZE {
+            return Ok(None);
+        }
+
+        let n = stream
+            .read_buf(&mut buf)
+            .await
+            .context("failed to read request")?;
+
+        if n == 0 {
+            bail!("connection closed before the request was complete");
+        }
+    }
+}
+
+fn parse_request_line(line: &str) -> Option<(&str, &str, &str)> {
+    let mut parts = line.split(' ');
+
+    let method = parts.next()?;
+    let uri = parts.next()?;
+    let version = parts.next()?;
+
+    if parts.next().is_some() || !version.starts_with("HTTP/1.") {
+        return None;
+    }
+
+    Some((method, uri, version))
+}
+
+/// Removes the case-insensitive `http://` scheme from `uri`.
+fn strip_scheme(uri: &str) -> Option<&str> {
+    const SCHEME: &str = "http://";
+
+    uri.get(..SCHEME.len())
+        .filter(|scheme| scheme.eq_ignore_ascii_case(SCHEME))
+        .map(|_| &uri[SCHEME.len()..])
+}
+
+/// The header lines which are forwarded to the origin server, each ending in
+/// `\r\n`. Besides the fixed hop-by-hop headers, those named in `Connection`
+/// are left out.
+fn end_to_end_headers<'a>(lines: impl Iterator<Item = &'a str>) -> String {
+    let headers: Vec<(&str, &str, &str)> = lines
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (name, value) = line.split_once(':').unwrap_or((line, ""));
+            (name.trim(), value, line)
+        })
+        .collect();
+
+    let listed: Vec<&str> = headers
+        .iter()
+        .filter(|(name, ..)| name.eq_ignore_ascii_case("connection"))
+        .flat_map(|(_, value, _)| value.split(',').map(str::trim))
+        .filter(|name| !name.eq_ignore_ascii_case("transfer-encoding"))
+        .collect();
+
+    let mut forwarded = String::new();
+
+    for (name, _, line) in headers {
+        let hop_by_hop = HOP_BY_HOP_HEADERS
+            .iter()
+            .chain(&listed)
+            .any(|header| name.eq_ignore_ascii_case(header));
+
+        if !hop_by_hop {
+            forwarded.push_str(line);
+            forwarded.push_str("\r\n");
+        }
+    }
+
+    forwarded
+}
+
+fn error_status(error: &ConnectError) -> &'static str {
+    match error {
+        ConnectError::Failed(ConnectFailReason::TimedOut) => "504 Gateway Timeout",
+        ConnectError::Failed(ConnectFailReason::ShuttingDown) => "503 Service Unavailable",
+        ConnectError::Failed(ConnectFailReason::Forbidden) | ConnectError::Rejected => {
+            "403 Forbidden"
+        }
+        _ => "502 Bad Gateway",
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str) -> Result<()> {
+    let response = format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+    stream.write_all(response.as_bytes()).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scheme_is_case_insensitive() {
+        assert_eq!(strip_scheme("http://example.com/"), Some("example.com/"));
+        assert_eq!(strip_scheme("HTTP://example.com/"), Some("example.com/"));
+        assert_eq!(strip_scheme("Http://example.com"), Some("example.com"));
+        assert_eq!(strip_scheme("https://example.com/"), None);
+        assert_eq!(strip_scheme("/index.html"), None);
+        assert_eq!(strip_scheme("http:/"), None);
+    }
+
+    #[test]
+    fn hop_by_hop_headers_are_removed() {
+        let head = "Host: example.com\r\n\
+                    Connection: keep-alive, X-Secret , Transfer-Encoding\r\n\
+                    Keep-Alive: timeout=5\r\n\
+                    Proxy-Authorization: Basic Zm9vOmJhcg==\r\n\
+                    x-secret: 1\r\n\
+                    Transfer-Encoding: chunked\r\n\
+                    Upgrade: websocket\r\n\
+                    Accept: */*";
+
+        assert_eq!(
+            end_to_end_headers(head.split("\r\n")),
+            "Host: example.com\r\nTransfer-Encoding: chunked\r\nAccept: */*\r\n"
+        );
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result, bail};
+use protocol::key_fingerprint;
+
+use crate::config::Config;
+
+/// Checks the public key from the encryption request against the pinned
+/// fingerprint, or against the known hosts file if nothing is pinned.
+///
+/// Keys of servers which aren't known yet are trusted and remembered.
+pub fn verify_server_key(config: &Config, public_key: &[u8]) -> Result<()> {
+    let fingerprint = key_fingerprint(public_key);
+    let host = host_entry(&config.server.host, config.server.port);
+
+    if let Some(pinned) = &config.server.fingerprint {
+        if *pinned != fingerprint {
+            bail!(
+                "server key of {host} does not match the pinned fingerprint, someone may be \
+                 intercepting the connection\n  expected: {pinned}\n  got:      {fingerprint}"
+            );
+        }
+
+        return Ok(());
+    }
+
+    let path = &config.known_hosts;
+
+    match lookup(path, &host)? {
+        Some(known) if known == fingerprint => Ok(()),
+        Some(known) => bail!(
+            "server key of {host} has changed, someone may be intercepting the connection\n  \
+             expected: {known}\n  got:      {fingerprint}\nIf the server key was replaced on \
+             purpose, remove {host} from {}",
+            path.display()
+        ),
+        None => {
+            log::warn!("Trusting new server {host} with key fingerprint {fingerprint}");
+
+            remember(path, &host, &fingerprint)
+                .with_context(|| format!("failed to update known hosts {}", path.display()))
+        }
+    }
+}
+
+fn host_entry(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    }
+}
+
+/// Finds the fingerprint of `host` in a file of `host fingerprint` lines.
+fn lookup(path: &Path, host: &str) -> Result<Option<String>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("failed to read known hosts {}", path.display()));
+        }
+    };
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let Some((entry, fingerprint)) = line.split_once(char::is_whitespace) else {
+            bail!("{}:{}: expected `host fingerprint`", path.display(), i + 1);
+        };
+
+        if entry == host {
+            return Ok(Some(fingerprint.trim().to_ascii_lowercase()));
+        }
+    }
+
+    Ok(None)
+}
+
+fn remember(path: &Path, host: &str, fingerprint: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{host} {fingerprint}")?;
+
+    Ok(())
+}
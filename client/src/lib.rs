@@ -0,0 +1,40 @@
+//! Tunnels connections through an rkp server. The `client` binary serves the
+//! local proxies and forwards, [`Tunnel`] opens connections from your own
+//! code:
+//!
+//! ```no_run
+//! # async fn example(config: client::Config) -> anyhow::Result<()> {
+//! use tokio::io::{AsyncReadExt, AsyncWriteExt};
+//!
+//! let tunnel = client::Tunnel::connect(&config).await?;
+//!
+//! let mut stream = tunnel.open_tcp("example.com:80".parse()?).await?;
+//! stream.write_all(b"GET / HTTP/1.0\r\nHost: example.com\r\n\r\n").await?;
+//!
+//! let mut response = Vec::new();
+//! stream.read_to_end(&mut response).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub use crate::{
+    config::Config,
+    tunnel::{ConnectError, Target, Tunnel, TunnelTcpStream, TunnelUdpSocket},
+};
+
+pub mod config;
+pub mod dns;
+pub mod forward;
+pub mod http;
+pub mod known_hosts;
+pub mod login;
+pub mod ping;
+pub mod reconnect;
+pub mod reverse;
+pub mod routing;
+pub mod socks;
+pub mod status;
+#[cfg(target_os = "linux")]
+pub mod transparent;
+pub mod tun;
+pub mod tunnel;
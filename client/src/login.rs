@@ -0,0 +1,137 @@
+use anyhow::{Result, bail, ensure};
+use protocol::{
+    Bounded, CompressionThreshold, Decode, Packet, VarInt,
+    clientbound::login::{
+        encryption_request::CEncryptionRequest, login_compression::CLoginCompression,
+        login_disconnect::CLoginDisconnect, login_success::CLoginFinished,
+    },
+    decode::PacketFrame,
+    packet_id::CURRENT_MC_PROTOCOL,
+    packet_io::PacketIo,
+    serverbound::{
+        handshake::intention::{HandshakeNextState, SIntention},
+        login::{
+            encryption_response::SEncryptionResponse, hello::SHello,
+            login_acknowledged::SLoginAcknowledged,
+        },
+        transfer::client_information::{
+            ChatMode, DisplayedSkinParts, MainHand, ParticleStatus, SClientInformation,
+        },
+    },
+};
+use rsa::{Pkcs1v15Encrypt, RsaPublicKey, pkcs8::DecodePublicKey, rand_core::OsRng};
+use tokio::net::TcpStream;
+
+use crate::{config::Config, known_hosts};
+
+/// Connects to the server and logs in, returning the encrypted connection
+/// ready for transfer packets.
+pub async fn login(config: &Config) -> Result<PacketIo> {
+    let host = config.server.host.as_str();
+    let port = config.server.port;
+
+    let stream = TcpStream::connect((host, port)).await?;
+    stream.set_nodelay(true)?;
+
+    let mut io = PacketIo::new(stream);
+
+    io.send_packet(&SIntention {
+        protocol_version: VarInt(CURRENT_MC_PROTOCOL as i32),
+        server_address: Bounded(host),
+        server_port: port,
+        next_state: HandshakeNextState::Login,
+    })
+    .await?;
+
+    io.send_packet(&SHello {
+        username: Bounded(&config.user.name),
+        uuid: config.user.public_uuid,
+    })
+    .await?;
+
+    encrypt_connection(&mut io, config).await?;
+
+    // Compression is optional, so the next packet is either of them.
+    loop {
+        let frame = io.recv_frame().await?;
+
+        if frame.id == CLoginCompression::ID.0 {
+            let CLoginCompression { threshold } = frame.decode()?;
+            io.set_compression(CompressionThreshold(threshold.0));
+
+            continue;
+        }
+
+        let CLoginFinished { username, .. } = decode_or_disconnect(frame)?;
+
+        ensure!(
+            username.0 == config.user.name,
+            "server logged us in as {} instead of {}",
+            username.0,
+            config.user.name
+        );
+
+        break;
+    }
+
+    io.send_packet(&SLoginAcknowledged).await?;
+
+    // The server checks the private UUID only now, after encryption is on.
+    io.send_packet(&SClientInformation {
+        private_uuid: config.user.private_uuid,
+        view_distance: 10,
+        chat_mode: ChatMode::default(),
+        chat_colors: true,
+        displayed_skin_parts: DisplayedSkinParts::new(),
+        main_hand: MainHand::default(),
+        enable_text_filtering: false,
+        allow_server_listings: true,
+        particle_status: ParticleStatus::default(),
+    })
+    .await?;
+
+    log::info!("Logged in to {host}:{port} as {}", config.user.name);
+
+    Ok(io)
+}
+
+async fn encrypt_connection(io: &mut PacketIo, config: &Config) -> Result<()> {
+    let frame = io.recv_frame().await?;
+    let CEncryptionRequest {
+        public_key,
+        verify_token,
+        ..
+    } = decode_or_disconnect(frame)?;
+
+    known_hosts::verify_server_key(config, public_key)?;
+
+    let public_key = RsaPublicKey::from_public_key_der(public_key)?;
+    let shared_secret: [u8; 16] = rand::random();
+
+    let shared_secret_enc = public_key.encrypt(&mut OsRng, Pkcs1v15Encrypt, &shared_secret)?;
+    let verify_token_enc = public_key.encrypt(&mut OsRng, Pkcs1v15Encrypt, verify_token)?;
+
+    io.send_packet(&SEncryptionResponse {
+        shared_secret: &shared_secret_enc,
+        verify_token: &verify_token_enc,
+    })
+    .await?;
+
+    io.enable_encryption(&shared_secret);
+
+    Ok(())
+}
+
+/// Decodes the frame as `P`, turning a [`CLoginDisconnect`] into an error with
+/// the reason the server gave.
+pub fn decode_or_disconnect<'a, P>(frame: &'a PacketFrame) -> Result<P>
+where
+    P: Packet + Decode<'a>,
+{
+    if frame.id == CLoginDisconnect::ID.0 && frame.id != P::ID.0 {
+        let CLoginDisconnect { reason } = frame.decode()?;
+        bail!("server refused the login: {}", reason.to_legacy_lossy());
+    }
+
+    frame.decode()
+}
@@ -1,34 +1,98 @@
 use anyhow::Result;
-use protocol::{
-    Bounded, VarInt,
-    clientbound::login::encryption_request::CEncryptionRequest,
-    packet_id::CURRENT_MC_PROTOCOL,
-    packet_io::PacketIo,
-    serverbound::handshake::intention::{HandshakeNextState, SIntention},
+use clap::Parser;
+use client::{
+    config::{Config, Routing, Server},
+    dns, forward, http, ping, reconnect, reverse,
+    routing::Router,
+    socks, status,
+    tunnel::Tunnel,
 };
-use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+
+#[cfg(target_os = "linux")]
+use client::tun;
+
+use crate::cli::{Cli, Command};
+
+pub mod cli;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    println!("Hello, world!");
+    let cli = Cli::parse();
+
+    simple_logger::init()?;
+
+    let source = cli.source();
+
+    match cli.command.unwrap_or(Command::Connect) {
+        Command::Connect => connect(&Config::load(&source)?).await,
+        Command::Ping => ping::ping(&Server::load(&source)?).await,
+        Command::Check => {
+            let config = Config::load(&source)?;
+            Router::new(&config.routing)?;
+
+            let (_, _, rtt) = reconnect::establish(&config).await?;
+
+            println!(
+                "Settings are valid and the server accepted the login ({} ms)",
+                rtt.as_millis()
+            );
+            Ok(())
+        }
+        Command::Route { targets } => {
+            let router = Router::new(&Routing::load(&source)?)?;
 
-    connect("0.0.0.0", 25565).await?;
+            for target in targets {
+                println!("{target}: {}", router.route(&target));
+            }
 
-    Ok(())
+            Ok(())
+        }
+    }
 }
 
-async fn connect(addr: &str, port: u16) -> Result<()> {
-    let mut io = PacketIo::new(TcpStream::connect((addr, port)).await?);
+async fn connect(config: &Config) -> Result<()> {
+    let (tunnel, supervisor) = Tunnel::start(config).await?;
+
+    let mut frontends = JoinSet::new();
+
+    if let Some(socks) = config.socks.clone() {
+        frontends.spawn(socks::serve(socks, tunnel.clone()));
+    }
+
+    if let Some(http) = config.http.clone() {
+        frontends.spawn(http::serve(http, tunnel.clone()));
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some(transparent) = config.transparent.clone() {
+        frontends.spawn(client::transparent::serve(transparent, tunnel.clone()));
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some(tun) = config.tun.clone() {
+        frontends.spawn(tun::serve(tun, tunnel.clone()));
+    }
+
+    if let Some(dns) = config.dns.clone() {
+        frontends.spawn(dns::serve(dns, tunnel.clone()));
+    }
+
+    for forward in config.forwards.iter().cloned() {
+        frontends.spawn(forward::serve(forward, tunnel.clone()));
+    }
 
-    io.send_packet(&SIntention {
-        protocol_version: VarInt(CURRENT_MC_PROTOCOL as i32),
-        server_address: Bounded(addr),
-        server_port: port,
-        next_state: HandshakeNextState::Login,
-    })
-    .await?;
+    for reverse in config.reverses.iter().cloned() {
+        frontends.spawn(reverse::serve(reverse, tunnel.clone()));
+    }
 
-    let next = io.recv_packet::<CEncryptionRequest>().await?;
+    if let Some(status) = config.status.clone() {
+        frontends.spawn(status::serve(status, tunnel.clone()));
+    }
 
-    Ok(())
+    // Runs until reconnecting gives up or a frontend can't accept anymore.
+    tokio::select! {
+        res = supervisor => res,
+        Some(res) = frontends.join_next() => res?,
+    }
 }
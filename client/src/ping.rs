@@ -0,0 +1,94 @@
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use protocol::{
+    Bounded, VarInt,
+    clientbound::status::{ping_response::CPongResponse, status_response::CStatusResponse},
+    packet_id::CURRENT_MC_PROTOCOL,
+    packet_io::PacketIo,
+    serverbound::{
+        handshake::intention::{HandshakeNextState, SIntention},
+        status::{ping_request::SPingRequest, status_request::SStatusRequest},
+    },
+};
+use serde::Deserialize;
+use tokio::net::TcpStream;
+use valence_text::Text;
+
+use crate::config::Server;
+
+#[derive(Debug, Deserialize)]
+struct Status {
+    version: Version,
+    players: Players,
+    description: Text,
+}
+
+#[derive(Debug, Deserialize)]
+struct Version {
+    name: String,
+    protocol: i32,
+}
+
+#[derive(Debug, Deserialize)]
+struct Players {
+    online: i32,
+    max: i32,
+}
+
+/// Asks the server for its server list entry like a Minecraft client would,
+/// and prints it along with the round trip time.
+pub async fn ping(server: &Server) -> Result<()> {
+    let host = server.host.as_str();
+    let port = server.port;
+
+    let stream = TcpStream::connect((host, port)).await?;
+    stream.set_nodelay(true)?;
+
+    let mut io = PacketIo::new(stream);
+
+    io.send_packet(&SIntention {
+        protocol_version: VarInt(CURRENT_MC_PROTOCOL as i32),
+        server_address: Bounded(host),
+        server_port: port,
+        next_state: HandshakeNextState::Status,
+    })
+    .await?;
+
+    io.send_packet(&SStatusRequest).await?;
+
+    // Servers with the status disabled just close the connection.
+    let CStatusResponse { json } = io
+        .recv_packet()
+        .await
+        .context("server didn't answer the status request")?;
+
+    let status: Status = serde_json::from_str(json).context("server sent an invalid status")?;
+
+    let start = Instant::now();
+    let payload = rand::random();
+
+    io.send_packet(&SPingRequest { payload }).await?;
+    let CPongResponse { payload: echoed } = io.recv_packet().await?;
+
+    let rtt = start.elapsed();
+
+    anyhow::ensure!(
+        echoed == payload,
+        "server answered the ping with a wrong payload"
+    );
+
+    println!("{host}:{port}");
+    println!(
+        "  version: {} (protocol {})",
+        status.version.name, status.version.protocol
+    );
+    println!(
+        "  players: {}/{}",
+        status.players.online, status.players.max
+    );
+    println!("  description: {}", status.description.to_legacy_lossy());
+    println!("  ping: {} ms", rtt.as_millis());
+
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use std::time::Duration;
+
+use anyhow::{Result, anyhow, bail};
+use tokio::{
+    task::JoinHandle,
+    time::{self, Instant},
+};
+
+use crate::{
+    config::{Config, Reconnect},
+    login,
+    tunnel::{Session, Tunnel},
+};
+
+/// How long logging in and getting the first answer from the server may take.
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Logs in and waits for the server to answer a ping, since the private UUID
+/// is only checked after the login. Returns the session, the task which ends
+/// when its connection is lost, and the round trip time of the ping.
+pub async fn establish(config: &Config) -> Result<(Session, JoinHandle<Result<()>>, Duration)> {
+    let deadline = Instant::now() + ATTEMPT_TIMEOUT;
+
+    let io = time::timeout_at(deadline, login::login(config))
+        .await
+        .map_err(|_| anyhow!("timed out logging in"))??;
+    let (session, closed) = Session::spawn(io);
+
+    // If the server doesn't answer, the reason is what ended the session.
+    match time::timeout_at(deadline, session.ping()).await {
+        Ok(Ok(rtt)) => Ok((session, closed, rtt)),
+        Ok(Err(e)) => {
+            closed.await??;
+            Err(e)
+        }
+        Err(_) => {
+            closed.abort();
+            bail!("timed out waiting for the server to answer");
+        }
+    }
+}
+
+/// Keeps `tunnel` connected, starting with the session ended by `closed`.
+/// Returns once reconnecting is disabled or gave up.
+pub async fn supervise(
+    config: &Config,
+    tunnel: &Tunnel,
+    mut closed: JoinHandle<Result<()>>,
+) -> Result<()> {
+    loop {
+        let error = match closed.await {
+            Ok(Ok(())) => anyhow!("server closed the connection"),
+            Ok(Err(e)) => e,
+            Err(e) => e.into(),
+        };
+
+        log::warn!("Lost the connection to the server: {error:#}");
+
+        if !config.reconnect.enabled {
+            tunnel.detach(&error, 0, true);
+            return Err(error);
+        }
+
+        tunnel.detach(&error, 0, false);
+        closed = reconnect(config, tunnel).await?;
+    }
+}
+
+/// Logs in again until it works or `max_attempts` is reached, waiting longer
+/// after every failed attempt.
+async fn reconnect(config: &Config, tunnel: &Tunnel) -> Result<JoinHandle<Result<()>>> {
+    let settings = &config.reconnect;
+    let mut failed_attempts = 0;
+
+    loop {
+        let delay = backoff(settings, failed_attempts);
+
+        log::info!(
+            "Reconnecting in {} ms (attempt {})",
+            delay.as_millis(),
+            failed_attempts + 1
+        );
+        time::sleep(delay).await;
+
+        match establish(config).await {
+            Ok((session, closed, _)) => {
+                tunnel.attach(session);
+                log::info!("Reconnected after {} attempt(s)", failed_attempts + 1);
+
+                return Ok(closed);
+            }
+            Err(e) => {
+                failed_attempts += 1;
+                log::warn!("Reconnect attempt {failed_attempts} failed: {e:#}");
+
+                let stop = settings.max_attempts != 0 && failed_attempts >= settings.max_attempts;
+                tunnel.detach(&e, failed_attempts, stop);
+
+                if stop {
+                    return Err(e.context(format!(
+                        "gave up reconnecting after {failed_attempts} attempts"
+                    )));
+                }
+            }
+        }
+    }
+}
+
+/// Doubles the delay with every failed attempt up to `max_delay`, then picks
+/// a random one from its upper half so that many clients losing the same
+/// server don't all come back at once.
+fn backoff(settings: &Reconnect, failed_attempts: u32) -> Duration {
+    let delay = settings
+        .initial_delay
+        .saturating_mul(1 << failed_attempts.min(31))
+        .min(settings.max_delay);
+
+    rand::random_range(delay / 2..=delay)
+}
@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use protocol::clientbound::transfer::data::BindFailReason;
+use tokio::net::TcpStream;
+
+use crate::{
+    config::Reverse,
+    tunnel::{self, BindError, State, Target, Tunnel, TunnelStream},
+};
+
+/// How long to wait before asking again when the server still holds the port
+/// for the previous connection.
+const REBIND_DELAY: Duration = Duration::from_secs(5);
+
+/// Asks the server to listen on `reverse.port` and connects every connection
+/// it accepts there to `reverse.target`. The server forgets the listener when
+/// the connection to it is lost, so it is requested again after reconnecting.
+pub async fn serve(reverse: Reverse, tunnel: Tunnel) -> Result<()> {
+    let mut rebinding = false;
+
+    loop {
+        let mut listener = match tunnel.bind(reverse.port).await {
+            Ok(listener) => listener,
+            Err(BindError::Closed) if tunnel.status().state != State::Stopped => continue,
+            // The server may not have noticed yet that the previous
+            // connection is gone.
+            Err(BindError::Failed(BindFailReason::AddrInUse)) if rebinding => {
+                log::warn!(
+                    "Server port {} is still in use, asking again in {} s",
+                    reverse.port,
+                    REBIND_DELAY.as_secs()
+                );
+                tokio::time::sleep(REBIND_DELAY).await;
+                continue;
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("failed to forward server port {}", reverse.port));
+            }
+        };
+
+        log::info!(
+            "Forwarding server port {} to {}",
+            listener.port,
+            reverse.target
+        );
+
+        while let Some(stream) = listener.accept().await {
+            let target = reverse.target.clone();
+
+            tokio::spawn(async move {
+                let peer_addr = stream.peer_addr;
+
+                if let Err(e) = handle(stream, &target).await {
+                    log::debug!("Reverse forward from {peer_addr} to {target} failed: {e}");
+                }
+            });
+        }
+
+        if tunnel.status().state == State::Stopped {
+            bail!("tunnel closed");
+        }
+
+        rebinding = true;
+
+        log::warn!(
+            "Server port {} is no longer forwarded, it is requested again after reconnecting",
+            reverse.port
+        );
+    }
+}
+
+async fn handle(stream: TunnelStream, target: &Target) -> Result<()> {
+    let local = match target {
+        Target::Addr(addr) => TcpStream::connect(addr).await?,
+        Target::Domain(domain, port) => TcpStream::connect((domain.as_str(), *port)).await?,
+    };
+
+    local.set_nodelay(true)?;
+
+    tunnel::relay(local, stream).await
+}
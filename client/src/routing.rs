@@ -0,0 +1,546 @@
+use std::{collections::HashSet, fmt, fs, net::IpAddr, path::Path, str::FromStr};
+
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Deserializer, de};
+
+use crate::{config::Routing, tunnel::Target};
+
+/// What happens to connections to a destination.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Connect from the server.
+    #[default]
+    Tunnel,
+    /// Connect from this machine, bypassing the server.
+    Direct,
+    /// Refuse the connection.
+    Reject,
+}
+
+/// An IP network like `10.0.0.0/8`, or a single address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+/// Either a single port like `443` or a range like `"8000-8100"`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+/// Decides per destination whether connections go through the tunnel,
+/// directly or nowhere. Rules are checked in order and the first matching one
+/// wins, connections no rule matches get the default action.
+#[derive(Debug, Default)]
+pub struct Router {
+    rules: Vec<Rule>,
+    default: Action,
+}
+
+#[derive(Debug)]
+struct Rule {
+    action: Action,
+    domains: HashSet<String>,
+    suffixes: HashSet<String>,
+    cidrs: Vec<Cidr>,
+    ports: Vec<PortRange>,
+}
+
+/// The outcome of routing a destination, with what led to it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Decision {
+    pub action: Action,
+    /// Index of the matching rule, `None` if the default applied.
+    pub rule: Option<usize>,
+    /// Which entry of the rule matched.
+    pub reason: String,
+}
+
+impl Router {
+    /// Builds the rules from the config, reading the list files they refer
+    /// to.
+    pub fn new(config: &Routing) -> Result<Self> {
+        let mut rules = Vec::with_capacity(config.rules.len());
+
+        for (i, rule) in config.rules.iter().enumerate() {
+            let mut compiled = Rule {
+                action: rule.action,
+                domains: rule.domain.iter().map(|domain| normalize(domain)).collect(),
+                suffixes: rule
+                    .domain_suffix
+                    .iter()
+                    .map(|suffix| normalize(strip_wildcard(suffix)))
+                    .collect(),
+                cidrs: rule.cidr.clone(),
+                ports: rule.ports.clone(),
+            };
+
+            for path in &rule.lists {
+                compiled.load_list(path).with_context(|| {
+                    format!(
+                        "`routing.rule[{i}].lists`: failed to load {}",
+                        path.display()
+                    )
+                })?;
+            }
+
+            rules.push(compiled);
+        }
+
+        Ok(Self {
+            rules,
+            default: config.default,
+        })
+    }
+
+    pub fn route(&self, target: &Target) -> Decision {
+        for (i, rule) in self.rules.iter().enumerate() {
+            if let Some(reason) = rule.matches(target) {
+                return Decision {
+                    action: rule.action,
+                    rule: Some(i),
+                    reason,
+                };
+            }
+        }
+
+        Decision {
+            action: self.default,
+            rule: None,
+            reason: "no rule matched".to_string(),
+        }
+    }
+}
+
+impl Rule {
+    /// Adds the entries of a list file: one IP address, CIDR or domain per
+    /// line. Domains match their subdomains too unless prefixed with `=`.
+    fn load_list(&mut self, path: &Path) -> Result<()> {
+        let text = fs::read_to_string(path)?;
+
+        for (i, line) in text.lines().enumerate() {
+            let entry = line.split('#').next().unwrap_or_default().trim();
+
+            if entry.is_empty() {
+                continue;
+            }
+
+            if let Some(domain) = entry.strip_prefix('=') {
+                self.domains.insert(normalize(domain));
+            } else if entry.contains('/') || entry.parse::<IpAddr>().is_ok() {
+                let cidr = entry
+                    .parse()
+                    .with_context(|| format!("line {}: invalid entry", i + 1))?;
+
+                self.cidrs.push(cidr);
+            } else {
+                self.suffixes.insert(normalize(strip_wildcard(entry)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Describes the matching entry, or `None` if the rule doesn't apply to
+    /// `target`. Domains only match domain targets and CIDRs only address
+    /// targets, nothing is resolved for matching.
+    fn matches(&self, target: &Target) -> Option<String> {
+        let (destination, port) = match target {
+            Target::Domain(domain, port) => {
+                let domain = normalize(domain);
+
+                let destination = if self.domains.contains(&domain) {
+                    Some(format!("domain {domain}"))
+                } else {
+                    suffixes(&domain)
+                        .find(|suffix| self.suffixes.contains(*suffix))
+                        .map(|suffix| format!("domain suffix {suffix}"))
+                };
+
+                (destination, *port)
+            }
+            Target::Addr(addr) => {
+                let destination = self
+                    .cidrs
+                    .iter()
+                    .find(|cidr| cidr.contains(addr.ip()))
+                    .map(|cidr| format!("CIDR {cidr}"));
+
+                (destination, addr.port())
+            }
+        };
+
+        let any_destination =
+            self.domains.is_empty() && self.suffixes.is_empty() && self.cidrs.is_empty();
+
+        if destination.is_none() && !any_destination {
+            return None;
+        }
+
+        if self.ports.is_empty() {
+            return destination;
+        }
+
+        let range = self.ports.iter().find(|range| range.contains(port))?;
+
+        Some(match destination {
+            Some(destination) => format!("{destination}, port {range}"),
+            None => format!("port {range}"),
+        })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // SOCKS clients may send IPv4 addresses in their IPv6 form.
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_eq(
+                u32::from(net).into(),
+                u32::from(ip).into(),
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(net.into(), ip.into(), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| anyhow!("expected an IP address or CIDR, got `{s}`"))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_len)
+                .ok_or_else(|| anyhow!("invalid prefix length in `{s}`"))?,
+            None => max_len,
+        };
+
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Port(u16),
+            Range(String),
+        }
+
+        let (start, end) = match Repr::deserialize(deserializer)? {
+            Repr::Port(port) => (port, port),
+            Repr::Range(range) => {
+                let parsed = match range.split_once('-') {
+                    Some((start, end)) => start.trim().parse().ok().zip(end.trim().parse().ok()),
+                    None => range.trim().parse().ok().map(|port| (port, port)),
+                };
+
+                parsed.ok_or_else(|| {
+                    de::Error::custom(format!("expected a port or `start-end`, got `{range}`"))
+                })?
+            }
+        };
+
+        if start > end {
+            return Err(de::Error::custom(format!(
+                "port range {start}-{end} is empty"
+            )));
+        }
+
+        Ok(Self { start, end })
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Tunnel => "tunnel",
+            Self::Direct => "direct",
+            Self::Reject => "reject",
+        })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.rule {
+            Some(i) => write!(f, "{} (routing.rule[{i}]: {})", self.action, self.reason),
+            None => write!(f, "{} ({})", self.action, self.reason),
+        }
+    }
+}
+
+/// Whether the first `prefix_len` of `bits` bits of both addresses are equal.
+fn prefix_eq(a: u128, b: u128, bits: u32, prefix_len: u8) -> bool {
+    let host_bits = bits - u32::from(prefix_len);
+
+    (a ^ b).checked_shr(host_bits).unwrap_or(0) == 0
+}
+
+/// Lowercases a domain and drops the dot of a fully qualified one.
+fn normalize(domain: &str) -> String {
+    domain.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Accepts `*.example.com` and `.example.com` for `example.com`.
+fn strip_wildcard(suffix: &str) -> &str {
+    let suffix = suffix.trim();
+    let suffix = suffix.strip_prefix('*').unwrap_or(suffix);
+
+    suffix.strip_prefix('.').unwrap_or(suffix)
+}
+
+/// `a.b.c`, `b.c` and `c` for `a.b.c`.
+fn suffixes(domain: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(domain), |domain| {
+        domain.split_once('.').map(|(_, parent)| parent)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router(config: &str) -> Router {
+        Router::new(&toml::from_str(config).unwrap()).unwrap()
+    }
+
+    fn route(router: &Router, target: &str) -> (Action, Option<usize>) {
+        let decision = router.route(&target.parse().unwrap());
+        (decision.action, decision.rule)
+    }
+
+    #[test]
+    fn domains_and_suffixes() {
+        let router = router(
+            r#"
+            [[rule]]
+            action = "direct"
+            domain = ["Exact.example.org."]
+            domain_suffix = ["*.lan", ".example.com"]
+            "#,
+        );
+
+        assert_eq!(
+            route(&router, "exact.example.org:80"),
+            (Action::Direct, Some(0))
+        );
+        assert_eq!(
+            route(&router, "sub.exact.example.org:80"),
+            (Action::Tunnel, None)
+        );
+        assert_eq!(route(&router, "example.com:443"), (Action::Direct, Some(0)));
+        assert_eq!(
+            route(&router, "a.b.EXAMPLE.com.:443"),
+            (Action::Direct, Some(0))
+        );
+        assert_eq!(route(&router, "notexample.com:443"), (Action::Tunnel, None));
+        assert_eq!(route(&router, "printer.lan:631"), (Action::Direct, Some(0)));
+        // Domains aren't resolved, so addresses never match them.
+        assert_eq!(route(&router, "192.0.2.1:80"), (Action::Tunnel, None));
+    }
+
+    #[test]
+    fn cidrs() {
+        let router = router(
+            r#"
+            [[rule]]
+            action = "reject"
+            cidr = ["10.0.0.0/8", "192.0.2.1", "fd00::/8", "0.0.0.0/0"]
+
+            [[rule]]
+            action = "direct"
+            cidr = ["::/0"]
+            "#,
+        );
+
+        assert_eq!(route(&router, "10.255.0.1:22"), (Action::Reject, Some(0)));
+        assert_eq!(route(&router, "[fd12::1]:22"), (Action::Reject, Some(0)));
+        assert_eq!(
+            route(&router, "[2001:db8::1]:22"),
+            (Action::Direct, Some(1))
+        );
+        // Mapped IPv4 addresses match IPv4 networks.
+        assert_eq!(
+            route(&router, "[::ffff:10.0.0.1]:22"),
+            (Action::Reject, Some(0))
+        );
+        assert_eq!(route(&router, "example.com:22"), (Action::Tunnel, None));
+
+        let cidr: Cidr = "192.0.2.0/25".parse().unwrap();
+        assert!(cidr.contains("192.0.2.127".parse().unwrap()));
+        assert!(!cidr.contains("192.0.2.128".parse().unwrap()));
+        assert!("192.0.2.0/33".parse::<Cidr>().is_err());
+        assert!("example.com/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn port_ranges() {
+        let router = router(
+            r#"
+            [[rule]]
+            action = "reject"
+            ports = [25, "6000-6010"]
+
+            [[rule]]
+            action = "direct"
+            domain_suffix = ["example.com"]
+            ports = ["443"]
+            "#,
+        );
+
+        assert_eq!(route(&router, "example.net:25"), (Action::Reject, Some(0)));
+        assert_eq!(route(&router, "192.0.2.1:6000"), (Action::Reject, Some(0)));
+        assert_eq!(route(&router, "192.0.2.1:6010"), (Action::Reject, Some(0)));
+        assert_eq!(route(&router, "192.0.2.1:6011"), (Action::Tunnel, None));
+        // Both the destination and the port have to match.
+        assert_eq!(
+            route(&router, "www.example.com:443"),
+            (Action::Direct, Some(1))
+        );
+        assert_eq!(route(&router, "www.example.com:80"), (Action::Tunnel, None));
+        assert_eq!(route(&router, "example.net:443"), (Action::Tunnel, None));
+
+        assert!(
+            toml::from_str::<Routing>("[[rule]]\naction = \"direct\"\nports = [\"9-8\"]").is_err()
+        );
+    }
+
+    #[test]
+    fn first_rule_wins() {
+        let router = router(
+            r#"
+            [[rule]]
+            action = "reject"
+            domain = ["ads.example.com"]
+
+            [[rule]]
+            action = "direct"
+            domain_suffix = ["example.com"]
+
+            [[rule]]
+            action = "reject"
+            domain_suffix = ["com"]
+            "#,
+        );
+
+        assert_eq!(
+            route(&router, "ads.example.com:443"),
+            (Action::Reject, Some(0))
+        );
+        assert_eq!(
+            route(&router, "www.example.com:443"),
+            (Action::Direct, Some(1))
+        );
+        assert_eq!(
+            route(&router, "example.org.com:443"),
+            (Action::Reject, Some(2))
+        );
+    }
+
+    #[test]
+    fn default_action() {
+        assert_eq!(
+            route(&Router::default(), "example.com:443"),
+            (Action::Tunnel, None)
+        );
+
+        let router = router(
+            r#"
+            default = "reject"
+
+            [[rule]]
+            action = "tunnel"
+            domain_suffix = ["example.com"]
+            "#,
+        );
+
+        assert_eq!(route(&router, "example.com:443"), (Action::Tunnel, Some(0)));
+        assert_eq!(route(&router, "example.net:443"), (Action::Reject, None));
+        assert_eq!(route(&router, "192.0.2.1:443"), (Action::Reject, None));
+    }
+
+    #[test]
+    fn lists() {
+        let path = std::env::temp_dir().join(format!("rkp-routing-{}.txt", std::process::id()));
+        fs::write(
+            &path,
+            "# Comment\n\nexample.com\n=exact.example.net # trailing\n10.0.0.0/8\n192.0.2.1\n",
+        )
+        .unwrap();
+
+        let config = format!(
+            "[[rule]]\naction = \"reject\"\nlists = [{:?}]",
+            path.display().to_string()
+        );
+        let router = router(&config);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            route(&router, "www.example.com:80"),
+            (Action::Reject, Some(0))
+        );
+        assert_eq!(
+            route(&router, "exact.example.net:80"),
+            (Action::Reject, Some(0))
+        );
+        assert_eq!(
+            route(&router, "www.exact.example.net:80"),
+            (Action::Tunnel, None)
+        );
+        assert_eq!(route(&router, "10.1.2.3:80"), (Action::Reject, Some(0)));
+        assert_eq!(route(&router, "192.0.2.1:80"), (Action::Reject, Some(0)));
+        assert_eq!(route(&router, "192.0.2.2:80"), (Action::Tunnel, None));
+    }
+}
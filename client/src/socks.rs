@@ -0,0 +1,394 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use anyhow::{Result, bail, ensure};
+use bytes::Bytes;
+use protocol::clientbound::transfer::data::ConnectFailReason;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::mpsc,
+    task::JoinSet,
+};
+
+use crate::{
+    config::Socks,
+    tunnel::{self, ConnectError, Target, Tunnel},
+};
+
+const VERSION: u8 = 5;
+
+const AUTH_NONE: u8 = 0x00;
+const AUTH_PASSWORD: u8 = 0x02;
+const AUTH_UNACCEPTABLE: u8 = 0xff;
+/// Version of the username/password subnegotiation from RFC 1929.
+const PASSWORD_VERSION: u8 = 1;
+
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_GENERAL_FAILURE: u8 = 0x01;
+const REP_NOT_ALLOWED: u8 = 0x02;
+const REP_NETWORK_UNREACHABLE: u8 = 0x03;
+const REP_HOST_UNREACHABLE: u8 = 0x04;
+const REP_CONNECTION_REFUSED: u8 = 0x05;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REP_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+const UDP_BUF_SIZE: usize = 65535;
+/// How many datagrams to one target may wait for its tunnel connection
+/// before further ones are dropped.
+const UDP_QUEUE_SIZE: usize = 64;
+
+/// Accepts SOCKS5 clients on `config.listen` and opens their connections
+/// through the tunnel.
+pub async fn serve(config: Socks, tunnel: Tunnel) -> Result<()> {
+    let listener = TcpListener::bind(config.listen).await?;
+
+    log::info!("SOCKS5 proxy listening on {}", config.listen);
+
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        let config = config.clone();
+        let tunnel = tunnel.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &config, &tunnel).await {
+                log::debug!("SOCKS5 connection from {peer_addr} failed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream, config: &Socks, tunnel: &Tunnel) -> Result<()> {
+    stream.set_nodelay(true)?;
+
+    authenticate(&mut stream, config).await?;
+
+    let [version, command, _reserved] = read_array(&mut stream).await?;
+    ensure!(version == VERSION, "unsupported SOCKS version {version}");
+
+    let target = match read_target(&mut stream).await? {
+        Some(target) => target,
+        None => return reply(&mut stream, REP_ADDRESS_NOT_SUPPORTED).await,
+    };
+
+    match command {
+        CMD_CONNECT => {}
+        CMD_UDP_ASSOCIATE => return associate(stream, target, tunnel).await,
+        _ => {
+            reply(&mut stream, REP_COMMAND_NOT_SUPPORTED).await?;
+            bail!("unsupported command {command}");
+        }
+    }
+
+    log::debug!("SOCKS5 connect to {target}");
+
+    let tunnel_stream = match tunnel.open(target, false).await {
+        Ok(tunnel_stream) => tunnel_stream,
+        Err(e) => {
+            reply(&mut stream, reply_code(&e)).await?;
+            return Err(e.into());
+        }
+    };
+
+    reply(&mut stream, REP_SUCCEEDED).await?;
+
+    tunnel::relay(stream, tunnel_stream).await
+}
+
+/// Relays datagrams between a local UDP socket and tunnel connections until
+/// the control connection is closed.
+///
+/// Every destination gets its own UDP connection on the server, replies are
+/// sent back with the destination in the SOCKS5 UDP header again.
+async fn associate(mut control: TcpStream, expected: Target, tunnel: &Tunnel) -> Result<()> {
+    let peer_addr = control.peer_addr()?;
+
+    // Datagrams have to come from where the control connection came from.
+    let expected_port = match expected {
+        Target::Addr(addr) if addr.port() != 0 => Some(addr.port()),
+        _ => None,
+    };
+
+    let socket = match UdpSocket::bind((control.local_addr()?.ip(), 0)).await {
+        Ok(socket) => Arc::new(socket),
+        Err(e) => {
+            reply(&mut control, REP_GENERAL_FAILURE).await?;
+            return Err(e.into());
+        }
+    };
+
+    reply_with_addr(&mut control, REP_SUCCEEDED, socket.local_addr()?).await?;
+
+    log::debug!("SOCKS5 UDP association for {peer_addr}");
+
+    let mut targets: HashMap<Target, mpsc::Sender<Bytes>> = HashMap::new();
+    let mut tasks = JoinSet::new();
+    let mut buf = vec![0; UDP_BUF_SIZE];
+    let mut control_buf = [0; 64];
+
+    loop {
+        tokio::select! {
+            // The association ends with the control connection, anything
+            // sent on it is ignored.
+            res = control.read(&mut control_buf) => {
+                if res? == 0 {
+                    return Ok(());
+                }
+            }
+            res = socket.recv_from(&mut buf) => {
+                let (len, from) = res?;
+
+                if from.ip() != peer_addr.ip() || expected_port.is_some_and(|port| port != from.port()) {
+                    continue;
+                }
+
+                let Some((target, data)) = parse_udp_header(&buf[..len]) else {
+                    continue;
+                };
+
+                let data = Bytes::copy_from_slice(data);
+
+                if let Some(tx) = targets.get(&target)
+                    && !tx.is_closed()
+                {
+                    // Like on a real network, datagrams are dropped when the
+                    // queue is full.
+                    let _ = tx.try_send(data);
+                    continue;
+                }
+
+                let (tx, rx) = mpsc::channel(UDP_QUEUE_SIZE);
+                let _ = tx.try_send(data);
+
+                tasks.spawn(associate_target(target.clone(), rx, socket.clone(), from, tunnel.clone()));
+                targets.insert(target, tx);
+            }
+            Some(_) = tasks.join_next() => {
+                targets.retain(|_, tx| !tx.is_closed());
+            }
+        }
+    }
+}
+
+/// Connects to `target` and relays the datagrams for it, wrapping the replies
+/// into SOCKS5 UDP headers.
+async fn associate_target(
+    target: Target,
+    datagrams: mpsc::Receiver<Bytes>,
+    socket: Arc<UdpSocket>,
+    client_addr: SocketAddr,
+    tunnel: Tunnel,
+) {
+    let stream = match tunnel.open(target.clone(), true).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::debug!("SOCKS5 UDP connect to {target} failed: {e}");
+            return;
+        }
+    };
+
+    let mut header = vec![0, 0, 0];
+    write_target(&mut header, &target);
+
+    tunnel::relay_udp(stream, datagrams, &socket, client_addr, &header).await;
+}
+
+/// Splits a datagram into its destination and payload. Fragmented datagrams
+/// aren't supported and are dropped, like most servers do.
+fn parse_udp_header(datagram: &[u8]) -> Option<(Target, &[u8])> {
+    let [_, _, fragment, address_type, rest @ ..] = datagram else {
+        return None;
+    };
+
+    if *fragment != 0 {
+        return None;
+    }
+
+    let (ip, rest): (IpAddr, _) = match *address_type {
+        ATYP_IPV4 => {
+            let (ip, rest) = rest.split_first_chunk::<4>()?;
+            ((*ip).into(), rest)
+        }
+        ATYP_IPV6 => {
+            let (ip, rest) = rest.split_first_chunk::<16>()?;
+            ((*ip).into(), rest)
+        }
+        ATYP_DOMAIN => {
+            let (len, rest) = rest.split_first()?;
+            let (domain, rest) = rest.split_at_checked(*len as usize)?;
+            let (port, data) = rest.split_first_chunk::<2>()?;
+
+            let domain = String::from_utf8(domain.to_vec()).ok()?;
+            let port = u16::from_be_bytes(*port);
+
+            let target = match domain.parse() {
+                Ok(ip) => Target::Addr(SocketAddr::new(ip, port)),
+                Err(_) => Target::Domain(domain, port),
+            };
+
+            return Some((target, data));
+        }
+        _ => return None,
+    };
+
+    let (port, data) = rest.split_first_chunk::<2>()?;
+
+    Some((
+        Target::Addr(SocketAddr::new(ip, u16::from_be_bytes(*port))),
+        data,
+    ))
+}
+
+/// Negotiates the authentication method and checks the credentials if the
+/// proxy requires them.
+async fn authenticate(stream: &mut TcpStream, config: &Socks) -> Result<()> {
+    let [version, method_count] = read_array(stream).await?;
+    ensure!(version == VERSION, "unsupported SOCKS version {version}");
+
+    let mut methods = vec![0; method_count as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let method = match config.username {
+        Some(_) => AUTH_PASSWORD,
+        None => AUTH_NONE,
+    };
+
+    if !methods.contains(&method) {
+        stream.write_all(&[VERSION, AUTH_UNACCEPTABLE]).await?;
+        bail!("client doesn't support the required authentication method");
+    }
+
+    stream.write_all(&[VERSION, method]).await?;
+
+    if method == AUTH_PASSWORD {
+        let [version] = read_array(stream).await?;
+        ensure!(
+            version == PASSWORD_VERSION,
+            "unsupported authentication version {version}"
+        );
+
+        let username = read_string(stream).await?;
+        let password = read_string(stream).await?;
+
+        let accepted = config.username.as_ref().map(String::as_bytes) == Some(&username)
+            && config.password.as_ref().map(String::as_bytes) == Some(&password);
+
+        stream
+            .write_all(&[PASSWORD_VERSION, if accepted { 0 } else { 1 }])
+            .await?;
+
+        ensure!(accepted, "wrong username or password");
+    }
+
+    Ok(())
+}
+
+/// Reads `ATYP DST.ADDR DST.PORT`. Returns `None` for unknown address types.
+async fn read_target(stream: &mut TcpStream) -> Result<Option<Target>> {
+    let [address_type] = read_array(stream).await?;
+
+    let target = match address_type {
+        ATYP_IPV4 => {
+            let ip: [u8; 4] = read_array(stream).await?;
+            let port = stream.read_u16().await?;
+
+            Target::Addr(SocketAddr::from((ip, port)))
+        }
+        ATYP_IPV6 => {
+            let ip: [u8; 16] = read_array(stream).await?;
+            let port = stream.read_u16().await?;
+
+            Target::Addr(SocketAddr::from((ip, port)))
+        }
+        ATYP_DOMAIN => {
+            let domain = String::from_utf8(read_string(stream).await?)?;
+            let port = stream.read_u16().await?;
+
+            // Some clients send literal addresses as domains.
+            match domain.parse() {
+                Ok(ip) => Target::Addr(SocketAddr::new(ip, port)),
+                Err(_) => Target::Domain(domain, port),
+            }
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some(target))
+}
+
+async fn reply(stream: &mut TcpStream, code: u8) -> Result<()> {
+    reply_with_addr(stream, code, SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))).await
+}
+
+async fn reply_with_addr(stream: &mut TcpStream, code: u8, addr: SocketAddr) -> Result<()> {
+    let mut buf = vec![VERSION, code, 0];
+    write_target(&mut buf, &Target::Addr(addr));
+    stream.write_all(&buf).await?;
+
+    Ok(())
+}
+
+/// Writes `ATYP ADDR PORT`.
+fn write_target(buf: &mut Vec<u8>, target: &Target) {
+    let port = match target {
+        Target::Addr(SocketAddr::V4(addr)) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        Target::Addr(SocketAddr::V6(addr)) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        Target::Domain(domain, port) => {
+            buf.push(ATYP_DOMAIN);
+            buf.push(domain.len() as u8);
+            buf.extend_from_slice(domain.as_bytes());
+            *port
+        }
+    };
+
+    buf.extend_from_slice(&port.to_be_bytes());
+}
+
+fn reply_code(error: &ConnectError) -> u8 {
+    match error {
+        ConnectError::Failed(ConnectFailReason::Unresolved | ConnectFailReason::TimedOut) => {
+            REP_HOST_UNREACHABLE
+        }
+        ConnectError::Failed(ConnectFailReason::Refused) => REP_CONNECTION_REFUSED,
+        ConnectError::Failed(ConnectFailReason::Unreachable) => REP_NETWORK_UNREACHABLE,
+        ConnectError::Failed(ConnectFailReason::Forbidden) | ConnectError::Rejected => {
+            REP_NOT_ALLOWED
+        }
+        ConnectError::Failed(ConnectFailReason::Other | ConnectFailReason::ShuttingDown)
+        | ConnectError::Closed => REP_GENERAL_FAILURE,
+    }
+}
+
+async fn read_array<const N: usize>(stream: &mut TcpStream) -> Result<[u8; N]> {
+    let mut buf = [0; N];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// Reads a string prefixed with its length as a single byte.
+async fn read_string(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let [len] = read_array(stream).await?;
+
+    let mut buf = vec![0; len as usize];
+    stream.read_exact(&mut buf).await?;
+
+    Ok(buf)
+}
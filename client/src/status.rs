@@ -0,0 +1,39 @@
+use anyhow::{Context, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use crate::{config::Status, tunnel::Tunnel};
+
+/// Answers every HTTP request on `config.listen` with the tunnel status as
+/// JSON, for health checks and scripts.
+pub async fn serve(config: Status, tunnel: Tunnel) -> Result<()> {
+    let listener = TcpListener::bind(config.listen)
+        .await
+        .with_context(|| format!("failed to listen on {}", config.listen))?;
+
+    log::info!("Status endpoint listening on {}", config.listen);
+
+    loop {
+        let (mut stream, peer_addr) = listener.accept().await?;
+        let body = serde_json::to_string(&tunnel.status())?;
+
+        tokio::spawn(async move {
+            // The request itself doesn't matter, but reading part of it
+            // keeps the client from getting a reset for unread data.
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await;
+
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n{body}",
+                body.len()
+            );
+
+            if let Err(e) = stream.write_all(response.as_bytes()).await {
+                log::debug!("Status request from {peer_addr} failed: {e}");
+            }
+        });
+    }
+}
@@ -0,0 +1,168 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpListener as StdTcpListener},
+};
+
+use anyhow::{Context, Result, bail};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{
+    config::Transparent,
+    tunnel::{self, Target, Tunnel},
+};
+
+const BACKLOG: i32 = 1024;
+
+/// Accepts connections which iptables redirected to `config.listen` and opens
+/// tunnel connections to where they were originally going.
+pub async fn serve(config: Transparent, tunnel: Tunnel) -> Result<()> {
+    let listener = bind(&config)?;
+
+    log::info!(
+        "Transparent proxy listening on {} ({})",
+        config.listen,
+        if config.tproxy { "TPROXY" } else { "REDIRECT" }
+    );
+
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        let tunnel = tunnel.clone();
+
+        let destination = match original_destination(&stream, &config) {
+            Ok(destination) => destination,
+            Err(e) => {
+                log::debug!("Transparent connection from {peer_addr} refused: {e}");
+                continue;
+            }
+        };
+
+        tokio::spawn(async move {
+            stream.set_nodelay(true)?;
+
+            let tunnel_stream = match tunnel
+                .open_redirected(Target::Addr(destination), false)
+                .await
+            {
+                Ok(tunnel_stream) => tunnel_stream,
+                Err(e) => {
+                    log::debug!(
+                        "Transparent connection from {peer_addr} to {destination} failed: {e}"
+                    );
+                    return Ok(());
+                }
+            };
+
+            if let Err(e) = tunnel::relay(stream, tunnel_stream).await {
+                log::debug!("Transparent connection from {peer_addr} to {destination} failed: {e}");
+            }
+
+            anyhow::Ok(())
+        });
+    }
+}
+
+fn bind(config: &Transparent) -> Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(config.listen),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+
+    socket.set_reuse_address(true)?;
+
+    // TPROXY hands over connections to foreign addresses, which the kernel
+    // only delivers to transparent sockets.
+    if config.tproxy {
+        let res = match config.listen {
+            SocketAddr::V4(_) => socket.set_ip_transparent_v4(true),
+            SocketAddr::V6(_) => socket.set_ip_transparent_v6(true),
+        };
+
+        res.context("failed to enable IP_TRANSPARENT, CAP_NET_ADMIN is required")?;
+    }
+
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&config.listen.into())
+        .with_context(|| format!("failed to listen on {}", config.listen))?;
+    socket.listen(BACKLOG)?;
+
+    Ok(TcpListener::from_std(StdTcpListener::from(socket))?)
+}
+
+/// Recovers where a redirected connection was going. `REDIRECT` rewrites the
+/// destination and remembers the original one in conntrack, `TPROXY` keeps it
+/// as the local address of the accepted socket.
+fn original_destination(stream: &TcpStream, config: &Transparent) -> Result<SocketAddr> {
+    let local_addr = stream.local_addr()?;
+
+    let destination = if config.tproxy {
+        local_addr
+    } else {
+        let socket = SockRef::from(stream);
+
+        let res = match local_addr {
+            SocketAddr::V4(_) => socket.original_dst_v4(),
+            SocketAddr::V6(_) => socket.original_dst_v6(),
+        };
+
+        // Without a NAT entry the connection wasn't redirected at all.
+        let destination = match res {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                bail!("connection wasn't redirected")
+            }
+            res => res.context("failed to get SO_ORIGINAL_DST")?,
+        };
+
+        destination
+            .as_socket()
+            .context("original destination isn't an IP address")?
+    };
+
+    // Connecting to the listener itself would loop back forever.
+    if destination == config.listen {
+        bail!("connection wasn't redirected");
+    }
+
+    Ok(destination)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A connection to a plain listener, accepted like one handed over by
+    /// `TPROXY`: the local address is where the client connected to.
+    async fn accepted() -> (TcpStream, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (_client, accepted) =
+            tokio::try_join!(TcpStream::connect(addr), listener.accept()).unwrap();
+
+        (accepted.0, addr)
+    }
+
+    #[tokio::test]
+    async fn tproxy_uses_local_addr() {
+        let (stream, addr) = accepted().await;
+        let config = Transparent {
+            listen: "127.0.0.1:12345".parse().unwrap(),
+            tproxy: true,
+        };
+
+        assert_eq!(original_destination(&stream, &config).unwrap(), addr);
+    }
+
+    #[tokio::test]
+    async fn listener_is_no_destination() {
+        let (stream, addr) = accepted().await;
+        let config = Transparent {
+            listen: addr,
+            tproxy: true,
+        };
+
+        assert!(original_destination(&stream, &config).is_err());
+    }
+}
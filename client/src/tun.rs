@@ -0,0 +1,1009 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io, mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use bytes::{Buf, Bytes};
+use smoltcp::{
+    iface::{Config, Interface, SocketHandle, SocketSet},
+    phy::{self, ChecksumCapabilities, Device, DeviceCapabilities, Medium},
+    socket::tcp::{self, State},
+    time::Instant,
+    wire::{
+        HardwareAddress, IpAddress, IpCidr, IpProtocol, IpRepr, Ipv4Packet, Ipv4Repr, Ipv6Packet,
+        Ipv6Repr, TcpPacket, UDP_HEADER_LEN, UdpPacket, UdpRepr,
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{
+        Notify,
+        mpsc::{self, error::TryRecvError},
+    },
+    task::{AbortHandle, JoinSet},
+};
+
+use crate::tunnel::{Target, Tunnel};
+
+/// Address of the userspace stack. Only the catch-all routes point at it, so
+/// it accepts packets for every destination.
+const STACK_IPV4: Ipv4Addr = Ipv4Addr::new(198, 18, 0, 1);
+const STACK_IPV6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+
+const MAX_PACKET_SIZE: usize = 65535;
+const HOP_LIMIT: u8 = 64;
+/// Size of the send and receive buffers of every TCP connection.
+const TCP_BUF_SIZE: usize = 65536;
+const RELAY_BUF_SIZE: usize = 16384;
+/// How many chunks may wait between a TCP connection and its tunnel
+/// connection in either direction.
+const TCP_QUEUE_SIZE: usize = 8;
+/// How many datagrams from one flow may wait for its tunnel connection before
+/// further ones are dropped.
+const UDP_QUEUE_SIZE: usize = 64;
+/// How many UDP replies may wait to be written to the device.
+const REPLY_QUEUE_SIZE: usize = 256;
+
+/// Where raw IP packets come from and go to.
+pub trait PacketSource: Send {
+    /// Receives exactly one packet. Has to be cancel safe.
+    fn recv(&mut self, buf: &mut [u8]) -> impl Future<Output = io::Result<usize>> + Send;
+
+    /// Sends exactly one packet.
+    fn send(&mut self, packet: &[u8]) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+/// Every read and write has to carry exactly one packet, like on a TUN
+/// device.
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> PacketSource for T {
+    async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.read(buf).await? {
+            0 => Err(io::ErrorKind::UnexpectedEof.into()),
+            n => Ok(n),
+        }
+    }
+
+    async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        if self.write(packet).await? != packet.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "packet was truncated",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Packets passed over channels, for feeding the stack from within the
+/// process instead of from a device.
+pub struct PacketChannel {
+    rx: mpsc::Receiver<Bytes>,
+    tx: mpsc::Sender<Bytes>,
+}
+
+impl PacketChannel {
+    /// Two connected ends, each holding up to `capacity` packets sent by the
+    /// other.
+    pub fn pair(capacity: usize) -> (Self, Self) {
+        let (a_tx, a_rx) = mpsc::channel(capacity);
+        let (b_tx, b_rx) = mpsc::channel(capacity);
+
+        (Self { rx: a_rx, tx: b_tx }, Self { rx: b_rx, tx: a_tx })
+    }
+}
+
+impl PacketSource for PacketChannel {
+    async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let packet = self.rx.recv().await.ok_or(io::ErrorKind::UnexpectedEof)?;
+        let buf = buf
+            .get_mut(..packet.len())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "packet is too large"))?;
+
+        buf.copy_from_slice(&packet);
+
+        Ok(packet.len())
+    }
+
+    async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.tx
+            .send(Bytes::copy_from_slice(packet))
+            .await
+            .map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+/// Opens the TUN device from `config` and routes everything arriving on it
+/// through the tunnel.
+#[cfg(target_os = "linux")]
+pub async fn serve(config: crate::config::Tun, tunnel: Tunnel) -> Result<()> {
+    let device = device::TunDevice::open(&config.name)
+        .with_context(|| format!("failed to open TUN device {}", config.name))?;
+
+    log::info!("Routing packets from TUN device {}", config.name);
+
+    run(device, config.mtu, tunnel).await
+}
+
+/// Terminates the TCP connections and UDP flows in the packets from `source`
+/// and opens a tunnel connection for each. Runs until `source` fails.
+pub async fn run(mut source: impl PacketSource, mtu: usize, tunnel: Tunnel) -> Result<()> {
+    let (replies, mut replies_rx) = mpsc::channel(REPLY_QUEUE_SIZE);
+    let mut stack = Stack::new(mtu, tunnel, replies);
+    let mut buf = vec![0; MAX_PACKET_SIZE];
+
+    loop {
+        let delay = stack.poll_delay();
+
+        let event = tokio::select! {
+            res = source.recv(&mut buf) => Event::Packet(res.context("failed to receive packet")?),
+            Some(packet) = replies_rx.recv() => Event::Reply(packet),
+            Some(_) = stack.udp_tasks.join_next() => Event::UdpClosed,
+            _ = stack.wake.notified() => Event::Wake,
+            _ = tokio::time::sleep(delay) => Event::Wake,
+        };
+
+        match event {
+            Event::Packet(len) => stack.receive(&buf[..len]),
+            Event::Reply(packet) => source.send(&packet).await?,
+            Event::UdpClosed => stack.udp.retain(|_, tx| !tx.is_closed()),
+            Event::Wake => {}
+        }
+
+        stack.poll();
+
+        for packet in mem::take(&mut stack.device.tx) {
+            source.send(&packet).await?;
+        }
+    }
+}
+
+enum Event {
+    Packet(usize),
+    Reply(Bytes),
+    UdpClosed,
+    Wake,
+}
+
+enum Downlink {
+    Data(Bytes),
+    Eof,
+    Reset,
+}
+
+struct TcpFlow {
+    key: (SocketAddr, SocketAddr),
+    /// Dropped once the local side has shut down.
+    uplink: Option<mpsc::Sender<Bytes>>,
+    downlink: mpsc::Receiver<Downlink>,
+    /// Set once the tunnel connection is done sending.
+    eof: bool,
+    pending: Bytes,
+    task: AbortHandle,
+}
+
+struct Stack {
+    iface: Interface,
+    device: Queue,
+    sockets: SocketSet<'static>,
+    tunnel: Tunnel,
+    /// Notified by flow tasks whenever their channels change.
+    wake: Arc<Notify>,
+    tcp: HashMap<SocketHandle, TcpFlow>,
+    tcp_keys: HashMap<(SocketAddr, SocketAddr), SocketHandle>,
+    udp: HashMap<(SocketAddr, SocketAddr), mpsc::Sender<Bytes>>,
+    udp_tasks: JoinSet<()>,
+    replies: mpsc::Sender<Bytes>,
+}
+
+impl Stack {
+    fn new(mtu: usize, tunnel: Tunnel, replies: mpsc::Sender<Bytes>) -> Self {
+        let mut device = Queue {
+            rx: None,
+            tx: Vec::new(),
+            mtu,
+        };
+
+        let mut iface = Interface::new(
+            Config::new(HardwareAddress::Ip),
+            &mut device,
+            Instant::now(),
+        );
+
+        iface.set_any_ip(true);
+        iface.update_ip_addrs(|addrs| {
+            addrs.push(IpCidr::new(STACK_IPV4.into(), 32)).unwrap();
+            addrs.push(IpCidr::new(STACK_IPV6.into(), 128)).unwrap();
+        });
+        iface
+            .routes_mut()
+            .add_default_ipv4_route(STACK_IPV4)
+            .unwrap();
+        iface
+            .routes_mut()
+            .add_default_ipv6_route(STACK_IPV6)
+            .unwrap();
+
+        Self {
+            iface,
+            device,
+            sockets: SocketSet::new(Vec::new()),
+            tunnel,
+            wake: Arc::new(Notify::new()),
+            tcp: HashMap::new(),
+            tcp_keys: HashMap::new(),
+            udp: HashMap::new(),
+            udp_tasks: JoinSet::new(),
+            replies,
+        }
+    }
+
+    fn poll_delay(&mut self) -> Duration {
+        self.iface
+            .poll_delay(Instant::now(), &self.sockets)
+            .map_or(Duration::from_secs(1), Duration::from)
+    }
+
+    fn receive(&mut self, packet: &[u8]) {
+        let Some((protocol, source, destination, payload)) = parse(packet) else {
+            return;
+        };
+
+        match protocol {
+            IpProtocol::Tcp => {
+                let Ok(segment) = TcpPacket::new_checked(payload) else {
+                    return;
+                };
+
+                let source = SocketAddr::new(source, segment.src_port());
+                let destination = SocketAddr::new(destination, segment.dst_port());
+
+                if segment.syn()
+                    && !segment.ack()
+                    && !self.tcp_keys.contains_key(&(source, destination))
+                {
+                    self.open_tcp(source, destination);
+                }
+
+                self.device.rx = Some(packet.to_vec());
+            }
+            IpProtocol::Udp => {
+                let Ok(datagram) = UdpPacket::new_checked(payload) else {
+                    return;
+                };
+
+                let Ok(repr) = UdpRepr::parse(
+                    &datagram,
+                    &source.into(),
+                    &destination.into(),
+                    &ChecksumCapabilities::default(),
+                ) else {
+                    return;
+                };
+
+                let source = SocketAddr::new(source, repr.src_port);
+                let destination = SocketAddr::new(destination, repr.dst_port);
+
+                self.receive_udp(source, destination, datagram.payload());
+            }
+            // Anything else can't be carried by the tunnel.
+            _ => {}
+        }
+    }
+
+    /// Listens for the handshake of a new connection and opens its tunnel
+    /// connection right away.
+    fn open_tcp(&mut self, source: SocketAddr, destination: SocketAddr) {
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; TCP_BUF_SIZE]),
+            tcp::SocketBuffer::new(vec![0; TCP_BUF_SIZE]),
+        );
+
+        socket.set_nagle_enabled(false);
+
+        if socket.listen(destination).is_err() {
+            return;
+        }
+
+        let handle = self.sockets.add(socket);
+
+        let (uplink, uplink_rx) = mpsc::channel(TCP_QUEUE_SIZE);
+        let (downlink, downlink_rx) = mpsc::channel(TCP_QUEUE_SIZE);
+
+        let task = tokio::spawn(tcp_flow(
+            self.tunnel.clone(),
+            destination,
+            uplink_rx,
+            downlink,
+            self.wake.clone(),
+        ));
+
+        self.tcp.insert(
+            handle,
+            TcpFlow {
+                key: (source, destination),
+                uplink: Some(uplink),
+                downlink: downlink_rx,
+                eof: false,
+                pending: Bytes::new(),
+                task: task.abort_handle(),
+            },
+        );
+        self.tcp_keys.insert((source, destination), handle);
+    }
+
+    fn receive_udp(&mut self, source: SocketAddr, destination: SocketAddr, payload: &[u8]) {
+        let datagram = Bytes::copy_from_slice(payload);
+
+        if let Some(tx) = self.udp.get(&(source, destination))
+            && !tx.is_closed()
+        {
+            let _ = tx.try_send(datagram);
+            return;
+        }
+
+        let (tx, rx) = mpsc::channel(UDP_QUEUE_SIZE);
+        let _ = tx.try_send(datagram);
+
+        self.udp_tasks.spawn(udp_flow(
+            self.tunnel.clone(),
+            source,
+            destination,
+            rx,
+            self.replies.clone(),
+        ));
+
+        self.udp.insert((source, destination), tx);
+    }
+
+    /// Lets the stack process received packets, moves data between the TCP
+    /// connections and their tunnel connections, and lets the stack send the
+    /// result.
+    fn poll(&mut self) {
+        self.iface
+            .poll(Instant::now(), &mut self.device, &mut self.sockets);
+
+        let mut closed = Vec::new();
+
+        for (&handle, flow) in &mut self.tcp {
+            let socket = self.sockets.get_mut::<tcp::Socket>(handle);
+
+            flow.download(socket);
+            flow.upload(socket);
+
+            if matches!(
+                socket.state(),
+                State::Listen | State::Closed | State::TimeWait
+            ) {
+                closed.push(handle);
+            }
+        }
+
+        for handle in closed {
+            let flow = self.tcp.remove(&handle).unwrap();
+            self.tcp_keys.remove(&flow.key);
+            self.sockets.remove(handle);
+
+            // The local side went away without shutting down, so the tunnel
+            // connection is reset rather than shut down.
+            if flow.uplink.is_some() {
+                flow.task.abort();
+            }
+        }
+
+        self.iface
+            .poll(Instant::now(), &mut self.device, &mut self.sockets);
+    }
+}
+
+impl TcpFlow {
+    fn download(&mut self, socket: &mut tcp::Socket) {
+        loop {
+            if !self.pending.is_empty() {
+                match socket.send_slice(&self.pending) {
+                    Ok(n) if n > 0 => self.pending.advance(n),
+                    _ => return,
+                }
+
+                continue;
+            }
+
+            if self.eof {
+                return;
+            }
+
+            match self.downlink.try_recv() {
+                Ok(Downlink::Data(data)) => self.pending = data,
+                Ok(Downlink::Eof) => {
+                    self.eof = true;
+                    socket.close();
+                }
+                Ok(Downlink::Reset) | Err(TryRecvError::Disconnected) => {
+                    self.eof = true;
+                    socket.abort();
+                }
+                Err(TryRecvError::Empty) => return,
+            }
+        }
+    }
+
+    fn upload(&mut self, socket: &mut tcp::Socket) {
+        let Some(uplink) = &self.uplink else {
+            return;
+        };
+
+        // Data stays in the receive buffer while the tunnel connection is
+        // busy, which shrinks the window of the local side.
+        while socket.can_recv() {
+            let Ok(permit) = uplink.try_reserve() else {
+                return;
+            };
+
+            let Ok(data) = socket.recv(|buf| {
+                let n = buf.len().min(RELAY_BUF_SIZE);
+                (n, Bytes::copy_from_slice(&buf[..n]))
+            }) else {
+                return;
+            };
+
+            permit.send(data);
+        }
+
+        if matches!(
+            socket.state(),
+            State::CloseWait | State::LastAck | State::Closing | State::TimeWait
+        ) {
+            self.uplink = None;
+        }
+    }
+}
+
+async fn tcp_flow(
+    tunnel: Tunnel,
+    destination: SocketAddr,
+    mut uplink: mpsc::Receiver<Bytes>,
+    downlink: mpsc::Sender<Downlink>,
+    wake: Arc<Notify>,
+) {
+    let stream = match tunnel
+        .open_redirected(Target::Addr(destination), false)
+        .await
+    {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::debug!("TUN connection to {destination} failed: {e}");
+
+            let _ = downlink.send(Downlink::Reset).await;
+            wake.notify_one();
+            return;
+        }
+    };
+
+    let (mut reader, mut writer) = stream.into_split();
+
+    let upload = async {
+        while let Some(data) = uplink.recv().await {
+            writer.send(data).await?;
+            wake.notify_one();
+        }
+
+        writer.shutdown().await
+    };
+
+    let download = async {
+        while let Some(data) = reader.recv().await? {
+            if downlink.send(Downlink::Data(data)).await.is_err() {
+                return Ok(());
+            }
+
+            wake.notify_one();
+        }
+
+        let _ = downlink.send(Downlink::Eof).await;
+        wake.notify_one();
+
+        anyhow::Ok(())
+    };
+
+    if let Err(e) = tokio::try_join!(upload, download) {
+        log::debug!("TUN connection to {destination} failed: {e}");
+
+        let _ = downlink.send(Downlink::Reset).await;
+        wake.notify_one();
+    }
+}
+
+async fn udp_flow(
+    tunnel: Tunnel,
+    source: SocketAddr,
+    destination: SocketAddr,
+    mut datagrams: mpsc::Receiver<Bytes>,
+    replies: mpsc::Sender<Bytes>,
+) {
+    let stream = match tunnel
+        .open_redirected(Target::Addr(destination), true)
+        .await
+    {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::debug!("TUN UDP flow to {destination} failed: {e}");
+            return;
+        }
+    };
+
+    let (mut reader, mut writer) = stream.into_split();
+
+    loop {
+        tokio::select! {
+            datagram = datagrams.recv() => {
+                let Some(datagram) = datagram else {
+                    return;
+                };
+
+                if writer.send(datagram).await.is_err() {
+                    return;
+                }
+            }
+            res = reader.recv() => {
+                let Ok(Some(datagram)) = res else {
+                    return;
+                };
+
+                let Some(packet) = udp_packet(destination, source, &datagram) else {
+                    continue;
+                };
+
+                if replies.send(packet).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Returns the transport protocol, source, destination and payload of an IP
+/// packet. Fragments are ignored.
+fn parse(packet: &[u8]) -> Option<(IpProtocol, IpAddr, IpAddr, &[u8])> {
+    match packet.first()? >> 4 {
+        4 => {
+            let packet = Ipv4Packet::new_checked(packet).ok()?;
+            let repr = Ipv4Repr::parse(&packet, &ChecksumCapabilities::default()).ok()?;
+
+            if packet.more_frags() || packet.frag_offset() != 0 {
+                return None;
+            }
+
+            Some((
+                repr.next_header,
+                repr.src_addr.into(),
+                repr.dst_addr.into(),
+                packet.payload(),
+            ))
+        }
+        6 => {
+            let packet = Ipv6Packet::new_checked(packet).ok()?;
+            let repr = Ipv6Repr::parse(&packet).ok()?;
+
+            Some((
+                repr.next_header,
+                repr.src_addr.into(),
+                repr.dst_addr.into(),
+                packet.payload(),
+            ))
+        }
+        _ => None,
+    }
+}
+
+fn udp_packet(source: SocketAddr, destination: SocketAddr, payload: &[u8]) -> Option<Bytes> {
+    let src_addr = IpAddress::from(source.ip());
+    let dst_addr = IpAddress::from(destination.ip());
+
+    if src_addr.version() != dst_addr.version() {
+        return None;
+    }
+
+    let ip = IpRepr::new(
+        src_addr,
+        dst_addr,
+        IpProtocol::Udp,
+        UDP_HEADER_LEN + payload.len(),
+        HOP_LIMIT,
+    );
+
+    if ip.buffer_len() > MAX_PACKET_SIZE {
+        return None;
+    }
+
+    let caps = ChecksumCapabilities::default();
+    let mut packet = vec![0; ip.buffer_len()];
+
+    ip.emit(&mut packet[..], &caps);
+
+    UdpRepr {
+        src_port: source.port(),
+        dst_port: destination.port(),
+    }
+    .emit(
+        &mut UdpPacket::new_unchecked(&mut packet[ip.header_len()..]),
+        &src_addr,
+        &dst_addr,
+        payload.len(),
+        |buf| buf.copy_from_slice(payload),
+        &caps,
+    );
+
+    Some(packet.into())
+}
+
+/// Hands single packets to the stack and collects the ones it sends.
+struct Queue {
+    rx: Option<Vec<u8>>,
+    tx: Vec<Vec<u8>>,
+    mtu: usize,
+}
+
+impl Device for Queue {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(RxToken, TxToken<'_>)> {
+        let packet = self.rx.take()?;
+        Some((RxToken(packet), TxToken(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<TxToken<'_>> {
+        Some(TxToken(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = self.mtu;
+        caps
+    }
+}
+
+struct RxToken(Vec<u8>);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F: FnOnce(&[u8]) -> R>(self, f: F) -> R {
+        f(&self.0)
+    }
+}
+
+struct TxToken<'a>(&'a mut Vec<Vec<u8>>);
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F) -> R {
+        let mut packet = vec![0; len];
+        let res = f(&mut packet);
+        self.0.push(packet);
+        res
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod device {
+    use std::{
+        fs::{File, OpenOptions},
+        io::{self, Read, Write},
+        mem,
+        os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+        pin::Pin,
+        task::{Context, Poll, ready},
+    };
+
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, unix::AsyncFd};
+
+    /// A TUN device without packet information headers, so every read and
+    /// write is one raw IP packet.
+    pub struct TunDevice(AsyncFd<File>);
+
+    impl TunDevice {
+        /// Attaches to the device `name`, creating it if it doesn't exist.
+        /// Needs `CAP_NET_ADMIN`.
+        pub fn open(name: &str) -> io::Result<Self> {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open("/dev/net/tun")?;
+
+            // SAFETY: `ifreq` is plain old data, all zeroes is a valid value.
+            let mut req: libc::ifreq = unsafe { mem::zeroed() };
+
+            // The config makes sure the name leaves room for the terminator.
+            for (dst, src) in req.ifr_name.iter_mut().zip(name.as_bytes()) {
+                *dst = *src as libc::c_char;
+            }
+
+            req.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+
+            // SAFETY: `req` is a valid `ifreq` which outlives the call.
+            if unsafe { libc::ioctl(file.as_raw_fd(), libc::TUNSETIFF, &req) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Self(AsyncFd::new(file)?))
+        }
+    }
+
+    impl AsyncRead for TunDevice {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            loop {
+                let mut guard = ready!(self.0.poll_read_ready(cx))?;
+
+                match guard.try_io(|file| file.get_ref().read(buf.initialize_unfilled())) {
+                    Ok(res) => {
+                        buf.advance(res?);
+                        return Poll::Ready(Ok(()));
+                    }
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+    }
+
+    impl AsyncWrite for TunDevice {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            loop {
+                let mut guard = ready!(self.0.poll_write_ready(cx))?;
+
+                match guard.try_io(|file| file.get_ref().write(buf)) {
+                    Ok(res) => return Poll::Ready(res),
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protocol::{
+        clientbound::transfer::data::{CData, CDataTypeByte},
+        packet_io::PacketIo,
+        serverbound::transfer::data::{SData, SDataTypeByte},
+    };
+    use smoltcp::socket::tcp::Socket;
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        time,
+    };
+
+    use super::*;
+    use crate::{routing::Router, tunnel::Session};
+
+    const HOST: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+    /// A tunnel to a server which echoes everything sent on its connections.
+    async fn echo_tunnel() -> Tunnel {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (client, server) = tokio::try_join!(
+            TcpStream::connect(listener.local_addr().unwrap()),
+            listener.accept()
+        )
+        .unwrap();
+
+        tokio::spawn(async move {
+            let (mut reader, mut writer) = PacketIo::new(server.0).into_split();
+            let mut next_connection_id = 0;
+
+            while let Ok(SData { data_type }) = reader.recv_packet().await {
+                let reply = match data_type {
+                    SDataTypeByte::Connect {
+                        request_id,
+                        ip,
+                        port,
+                        is_udp,
+                    } => {
+                        next_connection_id += 1;
+
+                        CDataTypeByte::Connect {
+                            request_id,
+                            ip,
+                            port,
+                            is_udp,
+                            connection_id: next_connection_id,
+                        }
+                    }
+                    SDataTypeByte::Process {
+                        connection_id,
+                        data,
+                    } => CDataTypeByte::Process {
+                        connection_id,
+                        data,
+                    },
+                    SDataTypeByte::Shutdown { connection_id } => {
+                        CDataTypeByte::Shutdown { connection_id }
+                    }
+                    _ => continue,
+                };
+
+                if writer
+                    .send_packet(&CData { data_type: reply })
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        });
+
+        let tunnel = Tunnel::new(Some(Duration::from_secs(5)), Router::default());
+        let (session, _) = Session::spawn(PacketIo::new(client));
+        tunnel.attach(session);
+
+        tunnel
+    }
+
+    /// The other side of the device: an interface like the one of the
+    /// system, sending to the stack through `device`.
+    struct Host {
+        device: PacketChannel,
+        iface: Interface,
+        queue: Queue,
+        sockets: SocketSet<'static>,
+    }
+
+    impl Host {
+        fn new(device: PacketChannel) -> Self {
+            let mut queue = Queue {
+                rx: None,
+                tx: Vec::new(),
+                mtu: 1500,
+            };
+            let mut iface =
+                Interface::new(Config::new(HardwareAddress::Ip), &mut queue, Instant::now());
+
+            iface.update_ip_addrs(|addrs| {
+                addrs.push(IpCidr::new(HOST.into(), 24)).unwrap();
+            });
+            iface
+                .routes_mut()
+                .add_default_ipv4_route(Ipv4Addr::new(10, 0, 0, 1))
+                .unwrap();
+
+            Self {
+                device,
+                iface,
+                queue,
+                sockets: SocketSet::new(Vec::new()),
+            }
+        }
+
+        /// Lets the interface handle what arrived and sends what it has to.
+        async fn poll(&mut self) {
+            self.iface
+                .poll(Instant::now(), &mut self.queue, &mut self.sockets);
+
+            for packet in mem::take(&mut self.queue.tx) {
+                self.device.send(&packet).await.unwrap();
+            }
+        }
+
+        /// Waits a little for a packet from the stack.
+        async fn receive(&mut self) -> Option<Vec<u8>> {
+            let mut buf = vec![0; MAX_PACKET_SIZE];
+            let len = time::timeout(Duration::from_millis(10), self.device.recv(&mut buf))
+                .await
+                .ok()?
+                .unwrap();
+
+            buf.truncate(len);
+            Some(buf)
+        }
+    }
+
+    fn start() -> Host {
+        let (device, stack) = PacketChannel::pair(64);
+
+        tokio::spawn(async move {
+            let _ = run(stack, 1500, echo_tunnel().await).await;
+        });
+
+        Host::new(device)
+    }
+
+    #[tokio::test]
+    async fn tcp_handshake_and_echo() {
+        let mut host = start();
+
+        let mut socket = Socket::new(
+            tcp::SocketBuffer::new(vec![0; TCP_BUF_SIZE]),
+            tcp::SocketBuffer::new(vec![0; TCP_BUF_SIZE]),
+        );
+        socket
+            .connect(host.iface.context(), (REMOTE, 80), 40000)
+            .unwrap();
+        let handle = host.sockets.add(socket);
+
+        let mut sent = false;
+        let mut echoed = Vec::new();
+
+        time::timeout(Duration::from_secs(5), async {
+            while echoed != b"ping" {
+                host.poll().await;
+
+                let socket = host.sockets.get_mut::<Socket>(handle);
+
+                if socket.state() == State::Established && !sent {
+                    socket.send_slice(b"ping").unwrap();
+                    sent = true;
+                }
+
+                if socket.can_recv() {
+                    socket
+                        .recv(|data| {
+                            echoed.extend_from_slice(data);
+                            (data.len(), ())
+                        })
+                        .unwrap();
+                }
+
+                host.queue.rx = host.receive().await;
+            }
+        })
+        .await
+        .expect("no echo through the stack");
+
+        assert!(sent);
+    }
+
+    #[tokio::test]
+    async fn udp_round_trip() {
+        let mut host = start();
+
+        let source = SocketAddr::new(HOST.into(), 5353);
+        let destination = SocketAddr::new(REMOTE.into(), 53);
+        let packet = udp_packet(source, destination, b"query").unwrap();
+        host.device.send(&packet).await.unwrap();
+
+        let reply = time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(reply) = host.receive().await {
+                    return reply;
+                }
+            }
+        })
+        .await
+        .expect("no UDP reply through the stack");
+
+        let (protocol, reply_source, reply_destination, payload) = parse(&reply).unwrap();
+        let datagram = UdpPacket::new_checked(payload).unwrap();
+
+        assert_eq!(protocol, IpProtocol::Udp);
+        assert_eq!(
+            SocketAddr::new(reply_source, datagram.src_port()),
+            destination
+        );
+        assert_eq!(
+            SocketAddr::new(reply_destination, datagram.dst_port()),
+            source
+        );
+        assert_eq!(datagram.payload(), b"query");
+    }
+}
@@ -0,0 +1,1996 @@
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    task::{Context, Poll, ready},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Context as _, Result, anyhow, bail};
+use bytes::Bytes;
+use protocol::{
+    Bounded, Packet,
+    clientbound::{
+        login::login_disconnect::CLoginDisconnect,
+        transfer::data::{BindFailReason, CData, CDataTypeByte, ConnectFailReason, ResetReason},
+    },
+    packet_io::{PacketIo, PacketReadHalf, PacketWriteHalf},
+    serverbound::transfer::data::{SData, SDataTypeByte},
+};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpStream, UdpSocket, lookup_host},
+    sync::{
+        self,
+        mpsc::{self, error::TrySendError},
+        oneshot, watch,
+    },
+    task::JoinHandle,
+};
+use tokio_util::sync::PollSender;
+
+use crate::{
+    config::{Config, Outage},
+    reconnect,
+    routing::{Action, Router},
+};
+
+/// How many packets may wait for the connection to the server.
+const OUTGOING_QUEUE_SIZE: usize = 256;
+/// How many chunks of data may wait for a local connection, or datagrams for
+/// a UDP one before further ones are dropped.
+const INCOMING_QUEUE_SIZE: usize = 256;
+/// Once this much data waits for the reader of a TCP connection through the
+/// tunnel, the server is asked to pause it.
+const PAUSE_BUFFERED: usize = 1 << 20;
+/// The server goes on once the reader is down to this much.
+const RESUME_BUFFERED: usize = PAUSE_BUFFERED / 4;
+/// A connection is reset once this much waits anyway, which leaves room for
+/// what was already on its way when it was paused.
+const MAX_BUFFERED: usize = 16 << 20;
+/// How many chunks that data may come in, as the server sends whatever the
+/// remote host wrote, however small.
+const STREAM_QUEUE_SIZE: usize = 4096;
+/// How many accepted connections may wait for a listener before further ones
+/// are reset.
+const BACKLOG_SIZE: usize = 64;
+const RELAY_BUF_SIZE: usize = 16384;
+/// Longest domain the connect packet can carry.
+const MAX_DOMAIN_LEN: usize = 255;
+/// How long connecting directly may take, like the server's default.
+const DIRECT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a direct UDP connection may stay without traffic, like the
+/// server's default.
+const DIRECT_UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// Opens connections through the server, logging in again whenever the
+/// connection to it is lost. Cloning it is cheap, all clones share the same
+/// connection.
+#[derive(Clone)]
+pub struct Tunnel {
+    shared: Arc<watch::Sender<Shared>>,
+    /// How long new connections wait for a session while reconnecting. `None`
+    /// fails them right away.
+    queue_timeout: Option<Duration>,
+    router: Arc<Router>,
+}
+
+struct Shared {
+    session: Option<Session>,
+    status: Status,
+}
+
+/// What the tunnel is doing, as logged and reported on the status endpoint.
+#[derive(Clone, Debug, Serialize)]
+pub struct Status {
+    pub state: State,
+    /// Failed login attempts since the last successful one.
+    pub failed_attempts: u32,
+    /// Successful logins so far, the first one included.
+    pub logins: u64,
+    pub last_error: Option<String>,
+    /// Unix time at which `state` was entered.
+    pub since: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Connecting,
+    Connected,
+    /// Gave up reconnecting, the client is shutting down.
+    Stopped,
+}
+
+/// Multiplexes connections opened through the server over a single logged in
+/// [`PacketIo`]. Cloning it is cheap, all clones share the same connection.
+#[derive(Clone)]
+pub struct Session {
+    outgoing: mpsc::Sender<Outgoing>,
+    pending: Arc<Mutex<Pending>>,
+}
+
+/// Where a connection through the tunnel should go.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Target {
+    Addr(SocketAddr),
+    /// Resolved by the server.
+    Domain(String, u16),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectError {
+    #[error("server failed to connect: {0:?}")]
+    Failed(ConnectFailReason),
+    #[error("tunnel is closed")]
+    Closed,
+    #[error("refused by a routing rule")]
+    Rejected,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BindError {
+    #[error("server failed to listen: {0:?}")]
+    Failed(BindFailReason),
+    #[error("tunnel is closed")]
+    Closed,
+}
+
+/// A port the server listens on for the client. The server stops listening
+/// when this is dropped.
+pub struct TunnelListener {
+    pub listener_id: u16,
+    /// The port the server listens on.
+    pub port: u16,
+    inbound: mpsc::Receiver<TunnelStream>,
+    control: mpsc::UnboundedSender<Control>,
+}
+
+/// A connection opened through the tunnel.
+pub struct TunnelStream {
+    pub connection_id: u16,
+    /// The address the server connected to, or accepted the connection from.
+    pub peer_addr: SocketAddr,
+    reader: StreamReader,
+    writer: StreamWriter,
+}
+
+/// A TCP connection through the tunnel as an [`AsyncRead`] and [`AsyncWrite`]
+/// stream. Shutting it down tells the remote host that no more data follows,
+/// dropping it without doing so resets the connection.
+pub struct TunnelTcpStream {
+    pub peer_addr: SocketAddr,
+    reader: StreamReader,
+    writer: StreamWriter,
+    sender: PollSender<Outgoing>,
+    /// Received, but not read yet.
+    buffered: Bytes,
+    eof: bool,
+}
+
+/// A UDP connection through the tunnel, used like a connected UDP socket.
+/// Datagrams keep their boundaries. The server closes it after a while
+/// without traffic.
+pub struct TunnelUdpSocket {
+    pub peer_addr: SocketAddr,
+    reader: sync::Mutex<StreamReader>,
+    writer: StreamWriter,
+}
+
+pub struct StreamReader {
+    incoming: mpsc::Receiver<Incoming>,
+    /// Shared with the writer.
+    closed: Arc<AtomicBool>,
+    /// `None` for UDP and direct connections, which don't need to pause the
+    /// server.
+    flow: Option<Flow>,
+}
+
+/// Lets the reader of a TCP connection through the tunnel resume it.
+struct Flow {
+    connection_id: u16,
+    backlog: Arc<Backlog>,
+    control: mpsc::UnboundedSender<Control>,
+}
+
+/// What waits for the reader of a TCP connection through the tunnel, shared
+/// between the reader and the dispatcher.
+#[derive(Default)]
+struct Backlog {
+    bytes: AtomicUsize,
+    /// Set while the server is asked to pause the connection.
+    paused: AtomicBool,
+}
+
+pub struct StreamWriter {
+    connection_id: u16,
+    outgoing: mpsc::Sender<Outgoing>,
+    /// Set once the id is no longer valid on the server, so dropping the
+    /// writer must not reset it.
+    closed: Arc<AtomicBool>,
+    shut_down: bool,
+    /// Tells the dispatcher when the writer is done, `None` for direct
+    /// connections which have none.
+    control: Option<mpsc::UnboundedSender<Control>>,
+}
+
+/// Sent to the dispatcher by the streams and listeners it handed out. The
+/// queue is unbounded, so dropping one of them never loses the message.
+enum Control {
+    Finished(Finished),
+    Unbind {
+        listener_id: u16,
+    },
+    /// A reader caught up with a paused connection.
+    Resume {
+        connection_id: u16,
+        /// Tells apart streams which had the same id at different times.
+        backlog: Arc<Backlog>,
+    },
+}
+
+/// Sent once a stream won't send anything anymore.
+struct Finished {
+    connection_id: u16,
+    /// Tells apart streams which had the same id at different times.
+    closed: Arc<AtomicBool>,
+    /// Whether the connection was reset rather than shut down.
+    reset: bool,
+}
+
+enum Outgoing {
+    Connect {
+        request_id: u16,
+        target: Target,
+        is_udp: bool,
+    },
+    Process {
+        connection_id: u16,
+        data: Bytes,
+    },
+    Shutdown {
+        connection_id: u16,
+    },
+    Reset {
+        connection_id: u16,
+    },
+    Bind {
+        request_id: u16,
+        port: u16,
+    },
+    Unbind {
+        listener_id: u16,
+    },
+    Ping {
+        request_id: u16,
+    },
+    Pause {
+        connection_id: u16,
+    },
+    Resume {
+        connection_id: u16,
+    },
+}
+
+enum Incoming {
+    Data(Bytes),
+    Eof,
+    Reset(ResetReason),
+}
+
+#[derive(Default)]
+struct Pending {
+    /// Set once the connection to the server is lost.
+    closed: bool,
+    next_request_id: u16,
+    requests: HashMap<u16, oneshot::Sender<Result<TunnelStream, ConnectError>>>,
+    binds: HashMap<u16, oneshot::Sender<Result<TunnelListener, BindError>>>,
+    pings: HashMap<u16, oneshot::Sender<()>>,
+}
+
+impl Pending {
+    /// Picks the next request id which isn't waiting for an answer, or `None`
+    /// once the connection to the server is lost.
+    fn allocate(&mut self) -> Option<u16> {
+        if self.closed {
+            return None;
+        }
+
+        while self.requests.contains_key(&self.next_request_id)
+            || self.binds.contains_key(&self.next_request_id)
+            || self.pings.contains_key(&self.next_request_id)
+        {
+            self.next_request_id = self.next_request_id.wrapping_add(1);
+        }
+
+        let request_id = self.next_request_id;
+        self.next_request_id = request_id.wrapping_add(1);
+        Some(request_id)
+    }
+}
+
+struct Slot {
+    incoming: mpsc::Sender<Incoming>,
+    closed: Arc<AtomicBool>,
+    /// `None` for UDP, whose datagrams are dropped instead.
+    backlog: Option<Arc<Backlog>>,
+    is_udp: bool,
+    /// Set once the server is done sending.
+    eof: bool,
+    /// Set once the local side is done sending.
+    shut_down: bool,
+}
+
+impl Tunnel {
+    /// Logs in to the server and keeps reconnecting in the background as
+    /// `config.reconnect` says. Only the connection settings of `config` are
+    /// used, none of the local listeners are started.
+    pub async fn connect(config: &Config) -> Result<Self> {
+        let (tunnel, supervisor) = Self::start(config).await?;
+
+        tokio::spawn(async move {
+            if let Err(e) = supervisor.await {
+                log::error!("Tunnel stopped: {e:#}");
+            }
+        });
+
+        Ok(tunnel)
+    }
+
+    /// Like [`Tunnel::connect`], but leaves reconnecting to the returned
+    /// future, which fails once reconnecting is disabled or gives up.
+    pub async fn start(
+        config: &Config,
+    ) -> Result<(Self, impl Future<Output = Result<()>> + Send + 'static)> {
+        let mut config = config.clone();
+        config.validate().context("invalid config")?;
+
+        let router = Router::new(&config.routing)?;
+        let queue_timeout = match config.reconnect.while_reconnecting {
+            Outage::Queue => Some(config.reconnect.queue_timeout),
+            Outage::Fail => None,
+        };
+
+        // Reconnecting only starts once the first login worked, settings
+        // which can't log in at all should fail right away.
+        let (session, closed, _) = reconnect::establish(&config).await?;
+        let tunnel = Self::new(queue_timeout, router);
+        tunnel.attach(session);
+
+        let supervisor = {
+            let tunnel = tunnel.clone();
+            async move { reconnect::supervise(&config, &tunnel, closed).await }
+        };
+
+        Ok((tunnel, supervisor))
+    }
+
+    /// Creates a tunnel without a session, connections wait for
+    /// [`Tunnel::attach`] as if it was reconnecting.
+    pub fn new(queue_timeout: Option<Duration>, router: Router) -> Self {
+        let shared = Shared {
+            session: None,
+            status: Status {
+                state: State::Connecting,
+                failed_attempts: 0,
+                logins: 0,
+                last_error: None,
+                since: unix_time(),
+            },
+        };
+
+        Self {
+            shared: Arc::new(watch::Sender::new(shared)),
+            queue_timeout,
+            router: Arc::new(router),
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        self.shared.borrow().status.clone()
+    }
+
+    /// Makes `session` the one new connections are opened on.
+    pub fn attach(&self, session: Session) {
+        self.shared.send_modify(|shared| {
+            shared.session = Some(session);
+            shared.status.state = State::Connected;
+            shared.status.failed_attempts = 0;
+            shared.status.logins += 1;
+            shared.status.since = unix_time();
+        });
+    }
+
+    /// Drops the current session after the connection to the server was
+    /// lost or a login failed. New connections wait for the next session,
+    /// unless `stop` says that there won't be one.
+    pub fn detach(&self, error: &anyhow::Error, failed_attempts: u32, stop: bool) {
+        self.shared.send_modify(|shared| {
+            let state = if stop {
+                State::Stopped
+            } else {
+                State::Connecting
+            };
+
+            if shared.status.state != state {
+                shared.status.since = unix_time();
+            }
+
+            shared.session = None;
+            shared.status.state = state;
+            shared.status.failed_attempts = failed_attempts;
+            shared.status.last_error = Some(format!("{error:#}"));
+        });
+    }
+
+    /// Opens a TCP connection to `target`, see [`Tunnel::open`].
+    pub async fn open_tcp(&self, target: Target) -> Result<TunnelTcpStream, ConnectError> {
+        let stream = self.open(target, false).await?;
+        let sender = PollSender::new(stream.writer.outgoing.clone());
+
+        Ok(TunnelTcpStream {
+            peer_addr: stream.peer_addr,
+            reader: stream.reader,
+            writer: stream.writer,
+            sender,
+            buffered: Bytes::new(),
+            eof: false,
+        })
+    }
+
+    /// Opens a UDP connection to `target`, see [`Tunnel::open`].
+    pub async fn open_udp(&self, target: Target) -> Result<TunnelUdpSocket, ConnectError> {
+        let stream = self.open(target, true).await?;
+
+        Ok(TunnelUdpSocket {
+            peer_addr: stream.peer_addr,
+            reader: sync::Mutex::new(stream.reader),
+            writer: stream.writer,
+        })
+    }
+
+    /// Opens a connection to `target` through the server, or directly if the
+    /// routing rules say so.
+    pub async fn open(&self, target: Target, is_udp: bool) -> Result<TunnelStream, ConnectError> {
+        self.open_routed(target, is_udp, true).await
+    }
+
+    /// Like [`open`](Self::open), for connections the system redirected to
+    /// this host, as with the transparent proxy or the TUN device. Connecting
+    /// to them directly would be redirected right back, so rules sending them
+    /// `direct` refuse them instead.
+    pub async fn open_redirected(
+        &self,
+        target: Target,
+        is_udp: bool,
+    ) -> Result<TunnelStream, ConnectError> {
+        self.open_routed(target, is_udp, false).await
+    }
+
+    async fn open_routed(
+        &self,
+        target: Target,
+        is_udp: bool,
+        allow_direct: bool,
+    ) -> Result<TunnelStream, ConnectError> {
+        let decision = self.router.route(&target);
+
+        match decision.action {
+            Action::Tunnel => {}
+            Action::Direct if !allow_direct => {
+                log::debug!("Refused redirected connection to {target}: {decision}");
+                return Err(ConnectError::Rejected);
+            }
+            Action::Direct => {
+                log::debug!("Connecting to {target} directly: {decision}");
+                return connect_direct(&target, is_udp).await;
+            }
+            Action::Reject => {
+                log::debug!("Refused connection to {target}: {decision}");
+                return Err(ConnectError::Rejected);
+            }
+        }
+
+        let session = self
+            .session(Some(self.queue_timeout.unwrap_or_default()))
+            .await
+            .ok_or(ConnectError::Closed)?;
+
+        session.connect(target, is_udp).await
+    }
+
+    /// Asks the server to listen on `port`, waiting for a session however
+    /// long it takes. The listener only lives as long as its session.
+    pub async fn bind(&self, port: u16) -> Result<TunnelListener, BindError> {
+        let session = self.session(None).await.ok_or(BindError::Closed)?;
+
+        session.bind(port).await
+    }
+
+    /// Waits up to `timeout` for a live session, or `None` if there is none
+    /// by then or the tunnel has stopped.
+    async fn session(&self, timeout: Option<Duration>) -> Option<Session> {
+        let mut shared = self.shared.subscribe();
+
+        let ready = shared.wait_for(|shared| {
+            shared.status.state == State::Stopped
+                || shared
+                    .session
+                    .as_ref()
+                    .is_some_and(|session| !session.is_closed())
+        });
+
+        let res = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, ready).await.ok()?,
+            None => ready.await,
+        };
+
+        res.ok()?.session.clone()
+    }
+}
+
+impl Session {
+    /// Starts relaying over `io`. The returned task finishes with the reason
+    /// the connection to the server was lost.
+    pub fn spawn(io: PacketIo) -> (Self, JoinHandle<Result<()>>) {
+        let (reader, writer) = io.into_split();
+        let (outgoing, outgoing_rx) = mpsc::channel(OUTGOING_QUEUE_SIZE);
+
+        let session = Self {
+            outgoing,
+            pending: Arc::default(),
+        };
+
+        let mut writer = tokio::spawn(write_loop(writer, outgoing_rx));
+        let (control, control_rx) = mpsc::unbounded_channel();
+        let dispatcher = Dispatcher {
+            session: session.clone(),
+            streams: HashMap::new(),
+            listeners: HashMap::new(),
+            control,
+            control_rx,
+        };
+
+        let pending = session.pending.clone();
+
+        let task = tokio::spawn(async move {
+            // A connection which can't be written to is as lost as one which
+            // can't be read from.
+            let res = tokio::select! {
+                res = dispatcher.run(reader) => res,
+                res = &mut writer => res.map_err(Into::into).and_then(|res| res),
+            };
+            writer.abort();
+
+            // Fails all connects which are still waiting for an answer.
+            let mut pending = pending.lock().unwrap();
+            pending.closed = true;
+            pending.requests.clear();
+            pending.binds.clear();
+            pending.pings.clear();
+
+            res
+        });
+
+        (session, task)
+    }
+
+    /// Opens a connection to `target` on the server side.
+    pub async fn connect(
+        &self,
+        target: Target,
+        is_udp: bool,
+    ) -> Result<TunnelStream, ConnectError> {
+        if let Target::Domain(domain, _) = &target
+            && domain.len() > MAX_DOMAIN_LEN
+        {
+            return Err(ConnectError::Failed(ConnectFailReason::Unresolved));
+        }
+
+        let (tx, rx) = oneshot::channel();
+
+        let request_id = {
+            let mut pending = self.pending.lock().unwrap();
+            let request_id = pending.allocate().ok_or(ConnectError::Closed)?;
+
+            pending.requests.insert(request_id, tx);
+            request_id
+        };
+
+        let connect = Outgoing::Connect {
+            request_id,
+            target,
+            is_udp,
+        };
+
+        if self.outgoing.send(connect).await.is_err() {
+            self.pending.lock().unwrap().requests.remove(&request_id);
+            return Err(ConnectError::Closed);
+        }
+
+        rx.await.unwrap_or(Err(ConnectError::Closed))
+    }
+
+    /// Asks the server to listen on `port` and hand over the connections it
+    /// accepts there.
+    pub async fn bind(&self, port: u16) -> Result<TunnelListener, BindError> {
+        let (tx, rx) = oneshot::channel();
+
+        let request_id = {
+            let mut pending = self.pending.lock().unwrap();
+            let request_id = pending.allocate().ok_or(BindError::Closed)?;
+
+            pending.binds.insert(request_id, tx);
+            request_id
+        };
+
+        if self
+            .outgoing
+            .send(Outgoing::Bind { request_id, port })
+            .await
+            .is_err()
+        {
+            self.pending.lock().unwrap().binds.remove(&request_id);
+            return Err(BindError::Closed);
+        }
+
+        rx.await.unwrap_or(Err(BindError::Closed))
+    }
+
+    /// Whether the connection to the server is lost.
+    pub fn is_closed(&self) -> bool {
+        self.pending.lock().unwrap().closed
+    }
+
+    /// Waits for the server to answer a ping and returns the round trip time.
+    /// An answer also means that the server accepted the login.
+    pub async fn ping(&self) -> Result<Duration> {
+        let (tx, rx) = oneshot::channel();
+
+        let request_id = {
+            let mut pending = self.pending.lock().unwrap();
+            let request_id = pending.allocate().ok_or(anyhow!("tunnel is closed"))?;
+
+            pending.pings.insert(request_id, tx);
+            request_id
+        };
+
+        let start = Instant::now();
+
+        if self
+            .outgoing
+            .send(Outgoing::Ping { request_id })
+            .await
+            .is_err()
+        {
+            self.pending.lock().unwrap().pings.remove(&request_id);
+            bail!("tunnel is closed");
+        }
+
+        rx.await.map_err(|_| anyhow!("tunnel is closed"))?;
+
+        Ok(start.elapsed())
+    }
+}
+
+impl TunnelListener {
+    /// Waits for the next connection, or `None` once the tunnel is closed.
+    pub async fn accept(&mut self) -> Option<TunnelStream> {
+        self.inbound.recv().await
+    }
+}
+
+impl Drop for TunnelListener {
+    fn drop(&mut self) {
+        // The dispatcher is gone only if the tunnel is.
+        let _ = self.control.send(Control::Unbind {
+            listener_id: self.listener_id,
+        });
+    }
+}
+
+impl Target {
+    /// Parses `host[:port]`, where the host may be an IPv6 address in
+    /// brackets. Without a port, `default_port` is used if there is one.
+    pub fn parse(authority: &str, default_port: Option<u16>) -> Option<Self> {
+        // A bare IPv6 address would otherwise lose its last group as the
+        // port.
+        if let Ok(ip) = authority.parse::<IpAddr>() {
+            return Some(Self::Addr(SocketAddr::new(ip, default_port?)));
+        }
+
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(rest) => {
+                let (host, rest) = rest.split_once(']')?;
+                (host, rest.strip_prefix(':'))
+            }
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+
+        let port = match port {
+            Some(port) => port.parse().ok()?,
+            None => default_port?,
+        };
+
+        if host.is_empty() {
+            return None;
+        }
+
+        Some(match host.parse() {
+            Ok(ip) => Self::Addr(SocketAddr::new(ip, port)),
+            Err(_) => Self::Domain(host.to_string(), port),
+        })
+    }
+}
+
+impl FromStr for Target {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s, None).ok_or_else(|| anyhow!("expected `host:port`, got `{s}`"))
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Addr(addr) => addr.fmt(f),
+            Self::Domain(domain, port) => write!(f, "{domain}:{port}"),
+        }
+    }
+}
+
+impl AsyncRead for TunnelTcpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Some(e) = self.reader.reset_error() {
+            self.buffered.clear();
+            return Poll::Ready(Err(e));
+        }
+
+        while self.buffered.is_empty() && !self.eof {
+            match ready!(self.reader.incoming.poll_recv(cx)) {
+                Some(Incoming::Data(data)) => {
+                    self.reader.taken(&data);
+                    self.buffered = data;
+                }
+                Some(Incoming::Eof) => self.eof = true,
+                Some(Incoming::Reset(reason)) => return Poll::Ready(Err(server_reset(reason))),
+                None => return Poll::Ready(Err(self.reader.closed_error())),
+            }
+        }
+
+        let len = self.buffered.len().min(buf.remaining());
+        buf.put_slice(&self.buffered.split_to(len));
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for TunnelTcpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.writer.shut_down {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "connection is already shut down",
+            )));
+        }
+
+        ready!(self.poll_reserve(cx))?;
+
+        let len = buf.len().min(RELAY_BUF_SIZE);
+        let connection_id = self.writer.connection_id;
+
+        self.send_item(Outgoing::Process {
+            connection_id,
+            data: Bytes::copy_from_slice(&buf[..len]),
+        })?;
+
+        Poll::Ready(Ok(len))
+    }
+
+    /// Written data is already on its way to the server.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.writer.shut_down {
+            return Poll::Ready(Ok(()));
+        }
+
+        ready!(self.poll_reserve(cx))?;
+
+        let connection_id = self.writer.connection_id;
+        self.send_item(Outgoing::Shutdown { connection_id })?;
+        self.writer.shut_down = true;
+        self.writer.notify_finished(false);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl TunnelTcpStream {
+    fn poll_reserve(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.sender
+            .poll_reserve(cx)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "tunnel is closed"))
+    }
+
+    fn send_item(&mut self, outgoing: Outgoing) -> io::Result<()> {
+        self.sender
+            .send_item(outgoing)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "tunnel is closed"))
+    }
+}
+
+impl TunnelUdpSocket {
+    /// Sends one datagram to `peer_addr`.
+    pub async fn send(&self, datagram: &[u8]) -> io::Result<()> {
+        self.writer
+            .send_outgoing(Outgoing::Process {
+                connection_id: self.writer.connection_id,
+                data: Bytes::copy_from_slice(datagram),
+            })
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.to_string()))
+    }
+
+    /// Receives one datagram into `buf` and returns its length. Datagrams
+    /// longer than `buf` are cut off, like with [`UdpSocket::recv`].
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let datagram = self
+            .reader
+            .lock()
+            .await
+            .recv()
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::ConnectionReset, e.to_string()))?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::ConnectionReset, "connection closed"))?;
+
+        let len = datagram.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram[..len]);
+
+        Ok(len)
+    }
+}
+
+impl TunnelStream {
+    pub async fn send(&mut self, data: Bytes) -> Result<()> {
+        self.writer.send(data).await
+    }
+
+    pub fn into_split(self) -> (StreamReader, StreamWriter) {
+        (self.reader, self.writer)
+    }
+}
+
+impl StreamReader {
+    /// Receives the next chunk of data, or `None` once the remote host has
+    /// shut down its side.
+    pub async fn recv(&mut self) -> Result<Option<Bytes>> {
+        if let Some(e) = self.reset_error() {
+            return Err(e.into());
+        }
+
+        match self.incoming.recv().await {
+            Some(Incoming::Data(data)) => {
+                self.taken(&data);
+                Ok(Some(data))
+            }
+            Some(Incoming::Eof) => Ok(None),
+            Some(Incoming::Reset(reason)) => Err(server_reset(reason).into()),
+            None => Err(self.closed_error().into()),
+        }
+    }
+
+    /// Fails a reset connection right away, throwing away whatever was still
+    /// queued for it. A reader reset for being too slow would otherwise take
+    /// as long as ever to get through its queue.
+    fn reset_error(&mut self) -> Option<io::Error> {
+        if !self.closed.load(Ordering::Acquire) {
+            return None;
+        }
+
+        while let Ok(incoming) = self.incoming.try_recv() {
+            if let Incoming::Reset(reason) = incoming {
+                return Some(server_reset(reason));
+            }
+        }
+
+        Some(self.closed_error())
+    }
+
+    /// Counts `data` as read, and resumes the connection once the reader has
+    /// caught up.
+    fn taken(&self, data: &Bytes) {
+        let Some(flow) = &self.flow else {
+            return;
+        };
+
+        let left = flow.backlog.bytes.fetch_sub(data.len(), Ordering::AcqRel) - data.len();
+
+        if left <= RESUME_BUFFERED && flow.backlog.paused.swap(false, Ordering::AcqRel) {
+            // The dispatcher is gone only if the tunnel is.
+            let _ = flow.control.send(Control::Resume {
+                connection_id: flow.connection_id,
+                backlog: flow.backlog.clone(),
+            });
+        }
+    }
+
+    /// Why the connection ended without a shutdown or reset from the server.
+    fn closed_error(&self) -> io::Error {
+        if self.closed.load(Ordering::Acquire) {
+            io::Error::new(io::ErrorKind::ConnectionReset, "connection was reset")
+        } else {
+            io::Error::new(io::ErrorKind::BrokenPipe, "tunnel is closed")
+        }
+    }
+}
+
+impl StreamWriter {
+    pub async fn send(&mut self, data: Bytes) -> Result<()> {
+        if self.shut_down {
+            bail!("connection is already shut down");
+        }
+
+        self.send_outgoing(Outgoing::Process {
+            connection_id: self.connection_id,
+            data,
+        })
+        .await
+    }
+
+    /// Tells the remote host that no more data follows.
+    pub async fn shutdown(&mut self) -> Result<()> {
+        if self.shut_down {
+            return Ok(());
+        }
+
+        self.shut_down = true;
+
+        self.send_outgoing(Outgoing::Shutdown {
+            connection_id: self.connection_id,
+        })
+        .await?;
+
+        self.notify_finished(false);
+
+        Ok(())
+    }
+
+    fn notify_finished(&self, reset: bool) {
+        if let Some(control) = &self.control {
+            // The dispatcher is gone only if the tunnel is.
+            let _ = control.send(Control::Finished(Finished {
+                connection_id: self.connection_id,
+                closed: self.closed.clone(),
+                reset,
+            }));
+        }
+    }
+
+    async fn send_outgoing(&self, outgoing: Outgoing) -> Result<()> {
+        self.outgoing
+            .send(outgoing)
+            .await
+            .map_err(|_| anyhow!("tunnel is closed"))
+    }
+}
+
+/// Closes the connection on the server unless it was shut down properly. The
+/// dispatcher sends the reset, as the outgoing queue could be full right now,
+/// and a direct connection sees the flag once the writer is gone.
+impl Drop for StreamWriter {
+    fn drop(&mut self) {
+        if !self.shut_down && !self.closed.swap(true, Ordering::AcqRel) {
+            self.notify_finished(true);
+        }
+    }
+}
+
+/// Connects to `target` from this machine, wrapped so that it can be used
+/// like a connection through the tunnel.
+async fn connect_direct(target: &Target, is_udp: bool) -> Result<TunnelStream, ConnectError> {
+    let addrs = match target {
+        Target::Addr(addr) => vec![*addr],
+        Target::Domain(domain, port) => lookup_host((domain.as_str(), *port))
+            .await
+            .map_err(|_| ConnectError::Failed(ConnectFailReason::Unresolved))?
+            .collect(),
+    };
+
+    if addrs.is_empty() {
+        return Err(ConnectError::Failed(ConnectFailReason::Unresolved));
+    }
+
+    match tokio::time::timeout(DIRECT_CONNECT_TIMEOUT, open_direct(&addrs, is_udp)).await {
+        Ok(Ok(stream)) => Ok(stream),
+        Ok(Err(e)) => Err(ConnectError::Failed(e.kind().into())),
+        Err(_) => Err(ConnectError::Failed(ConnectFailReason::TimedOut)),
+    }
+}
+
+async fn open_direct(addrs: &[SocketAddr], is_udp: bool) -> io::Result<TunnelStream> {
+    let (incoming, incoming_rx) = mpsc::channel(INCOMING_QUEUE_SIZE);
+    let (outgoing, outgoing_rx) = mpsc::channel(INCOMING_QUEUE_SIZE);
+    let closed = Arc::new(AtomicBool::new(false));
+
+    let peer_addr = if is_udp {
+        let peer_addr = addrs[0];
+        let local_addr = match peer_addr {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+
+        let socket = UdpSocket::bind(local_addr).await?;
+        socket.connect(peer_addr).await?;
+
+        tokio::spawn(bridge_udp(socket, incoming, outgoing_rx));
+        peer_addr
+    } else {
+        let stream = TcpStream::connect(addrs).await?;
+        stream.set_nodelay(true)?;
+        let peer_addr = stream.peer_addr()?;
+
+        tokio::spawn(bridge_tcp(stream, incoming, outgoing_rx, closed.clone()));
+        peer_addr
+    };
+
+    Ok(TunnelStream {
+        connection_id: 0,
+        peer_addr,
+        reader: StreamReader {
+            incoming: incoming_rx,
+            closed: closed.clone(),
+            flow: None,
+        },
+        writer: StreamWriter {
+            connection_id: 0,
+            outgoing,
+            closed,
+            shut_down: false,
+            control: None,
+        },
+    })
+}
+
+/// Plays the server's part for a direct TCP connection: writes what the
+/// stream sends to `socket` and hands over what comes back.
+async fn bridge_tcp(
+    socket: TcpStream,
+    incoming: mpsc::Sender<Incoming>,
+    mut outgoing: mpsc::Receiver<Outgoing>,
+    closed: Arc<AtomicBool>,
+) {
+    let (mut read, mut write) = socket.into_split();
+
+    let download = tokio::spawn(async move {
+        let mut buf = vec![0; RELAY_BUF_SIZE];
+
+        loop {
+            let res = tokio::select! {
+                res = read.read(&mut buf) => res,
+                () = incoming.closed() => return,
+            };
+
+            let message = match res {
+                Ok(0) => Incoming::Eof,
+                Ok(n) => Incoming::Data(Bytes::copy_from_slice(&buf[..n])),
+                Err(e) => Incoming::Reset(e.kind().into()),
+            };
+
+            let done = !matches!(message, Incoming::Data(_));
+
+            if incoming.send(message).await.is_err() || done {
+                return;
+            }
+        }
+    });
+
+    // A dropped writer after a shutdown still waits for the remote host to
+    // finish, one dropped without a shutdown ends both directions right away.
+    let reset = loop {
+        let res = match outgoing.recv().await {
+            Some(Outgoing::Process { data, .. }) => write.write_all(&data).await,
+            Some(Outgoing::Shutdown { .. }) => write.shutdown().await,
+            Some(_) => break true,
+            None => break closed.load(Ordering::Acquire),
+        };
+
+        if res.is_err() {
+            break true;
+        }
+    };
+
+    if reset {
+        download.abort();
+    }
+}
+
+/// Plays the server's part for a direct UDP connection, ending it once no
+/// datagrams passed for a while.
+async fn bridge_udp(
+    socket: UdpSocket,
+    incoming: mpsc::Sender<Incoming>,
+    mut outgoing: mpsc::Receiver<Outgoing>,
+) {
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+
+    loop {
+        tokio::select! {
+            res = socket.recv(&mut buf) => {
+                let message = match res {
+                    Ok(n) => Incoming::Data(Bytes::copy_from_slice(&buf[..n])),
+                    Err(e) => Incoming::Reset(e.kind().into()),
+                };
+
+                let done = !matches!(message, Incoming::Data(_));
+
+                if incoming.send(message).await.is_err() || done {
+                    return;
+                }
+            }
+            outgoing = outgoing.recv() => {
+                let Some(Outgoing::Process { data, .. }) = outgoing else {
+                    return;
+                };
+
+                if let Err(e) = socket.send(&data).await {
+                    log::debug!("Failed to send direct UDP datagram: {e}");
+                }
+            }
+            () = tokio::time::sleep(DIRECT_UDP_IDLE_TIMEOUT) => {
+                let _ = incoming.send(Incoming::Reset(ResetReason::Idle)).await;
+                return;
+            }
+        }
+    }
+}
+
+fn server_reset(reason: ResetReason) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionReset,
+        format!("connection reset by server: {reason:?}"),
+    )
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// Copies data between a local TCP connection and a tunnel connection until
+/// both sides are done.
+pub async fn relay(local: TcpStream, stream: TunnelStream) -> Result<()> {
+    relay_until(local, stream, false).await
+}
+
+/// Like [`relay`], but done as soon as the remote host is. The local
+/// connection is closed then, even if the local side would still send.
+pub async fn relay_until_remote_done(local: TcpStream, stream: TunnelStream) -> Result<()> {
+    relay_until(local, stream, true).await
+}
+
+async fn relay_until(local: TcpStream, stream: TunnelStream, remote_done: bool) -> Result<()> {
+    let (mut local_read, mut local_write) = local.into_split();
+    let (mut reader, mut writer) = stream.into_split();
+
+    let upload = async {
+        let mut buf = vec![0; RELAY_BUF_SIZE];
+
+        loop {
+            let n = local_read.read(&mut buf).await?;
+
+            if n == 0 {
+                return writer.shutdown().await;
+            }
+
+            writer.send(Bytes::copy_from_slice(&buf[..n])).await?;
+        }
+    };
+
+    let download = async {
+        while let Some(data) = reader.recv().await? {
+            local_write.write_all(&data).await?;
+        }
+
+        local_write.shutdown().await?;
+
+        anyhow::Ok(())
+    };
+
+    // Whichever side fails first drops the other, which resets the tunnel
+    // connection and closes the local socket.
+    if !remote_done {
+        tokio::try_join!(upload, download)?;
+        return Ok(());
+    }
+
+    tokio::pin!(download);
+
+    tokio::select! {
+        res = &mut download => res,
+        res = upload => {
+            res?;
+            download.await
+        }
+    }
+}
+
+/// Sends datagrams through a UDP tunnel connection and the replies to `peer`,
+/// each prefixed with `header`, until either side goes away.
+pub async fn relay_udp(
+    stream: TunnelStream,
+    mut datagrams: mpsc::Receiver<Bytes>,
+    socket: &UdpSocket,
+    peer: SocketAddr,
+    header: &[u8],
+) {
+    let (mut reader, mut writer) = stream.into_split();
+
+    loop {
+        tokio::select! {
+            datagram = datagrams.recv() => {
+                let Some(datagram) = datagram else {
+                    return;
+                };
+
+                if writer.send(datagram).await.is_err() {
+                    return;
+                }
+            }
+            res = reader.recv() => {
+                let Ok(Some(datagram)) = res else {
+                    return;
+                };
+
+                let mut reply = Vec::with_capacity(header.len() + datagram.len());
+                reply.extend_from_slice(header);
+                reply.extend_from_slice(&datagram);
+
+                if let Err(e) = socket.send_to(&reply, peer).await {
+                    log::debug!("Failed to send UDP reply to {peer}: {e}");
+                }
+            }
+        }
+    }
+}
+
+struct Dispatcher {
+    session: Session,
+    streams: HashMap<u16, Slot>,
+    listeners: HashMap<u16, mpsc::Sender<TunnelStream>>,
+    /// Given to every stream and listener, to hear when they are done.
+    control: mpsc::UnboundedSender<Control>,
+    control_rx: mpsc::UnboundedReceiver<Control>,
+}
+
+impl Dispatcher {
+    async fn run(mut self, mut reader: PacketReadHalf) -> Result<()> {
+        loop {
+            tokio::select! {
+                frame = reader.recv_frame() => {
+                    let frame = frame?;
+
+                    // The server may still reject the private UUID right
+                    // after login.
+                    if frame.id == CLoginDisconnect::ID.0 {
+                        let CLoginDisconnect { reason } = frame.decode()?;
+                        bail!("server closed the connection: {}", reason.to_legacy_lossy());
+                    }
+
+                    let CData { data_type } = frame.decode()?;
+                    self.handle_packet(data_type).await;
+                }
+                Some(control) = self.control_rx.recv() => self.handle_control(control).await,
+            }
+        }
+    }
+
+    async fn handle_packet(&mut self, data_type: CDataTypeByte<'_>) {
+        match data_type {
+            CDataTypeByte::Connect {
+                request_id,
+                ip,
+                port,
+                is_udp,
+                connection_id,
+            } => {
+                let stream = self.open_stream(connection_id, SocketAddr::new(ip, port), is_udp);
+
+                // Nobody waits for the answer anymore, so dropping the stream
+                // resets the connection again.
+                if let Some(tx) = self.take_request(request_id) {
+                    let _ = tx.send(Ok(stream));
+                }
+            }
+            CDataTypeByte::ConnectFailed { request_id, reason } => {
+                if let Some(tx) = self.take_request(request_id) {
+                    let _ = tx.send(Err(ConnectError::Failed(reason)));
+                }
+            }
+            CDataTypeByte::Process {
+                connection_id,
+                data,
+            } => {
+                self.forward(connection_id, Incoming::Data(Bytes::copy_from_slice(data)))
+                    .await
+            }
+            CDataTypeByte::Shutdown { connection_id } => {
+                self.forward(connection_id, Incoming::Eof).await
+            }
+            CDataTypeByte::Reset {
+                connection_id,
+                reason,
+            } => {
+                if let Some(slot) = self.streams.remove(&connection_id) {
+                    slot.closed.store(true, Ordering::Release);
+                    // A reader too far behind to take this sees the reset
+                    // once it has caught up.
+                    let _ = slot.incoming.try_send(Incoming::Reset(reason));
+                }
+            }
+            CDataTypeByte::Bound {
+                request_id,
+                listener_id,
+                port,
+            } => {
+                let (inbound, inbound_rx) = mpsc::channel(BACKLOG_SIZE);
+                self.listeners.insert(listener_id, inbound);
+
+                let listener = TunnelListener {
+                    listener_id,
+                    port,
+                    inbound: inbound_rx,
+                    control: self.control.clone(),
+                };
+
+                // Dropping the listener unbinds it again.
+                let tx = self
+                    .session
+                    .pending
+                    .lock()
+                    .unwrap()
+                    .binds
+                    .remove(&request_id);
+
+                if let Some(tx) = tx {
+                    let _ = tx.send(Ok(listener));
+                }
+            }
+            CDataTypeByte::BindFailed { request_id, reason } => {
+                let tx = self
+                    .session
+                    .pending
+                    .lock()
+                    .unwrap()
+                    .binds
+                    .remove(&request_id);
+
+                if let Some(tx) = tx {
+                    let _ = tx.send(Err(BindError::Failed(reason)));
+                }
+            }
+            CDataTypeByte::Inbound {
+                listener_id,
+                connection_id,
+                ip,
+                port,
+            } => {
+                let stream = self.open_stream(connection_id, SocketAddr::new(ip, port), false);
+
+                // A stream which can't be handed over is dropped, which resets
+                // the connection.
+                if let Entry::Occupied(listener) = self.listeners.entry(listener_id)
+                    && let Err(TrySendError::Closed(_)) = listener.get().try_send(stream)
+                {
+                    listener.remove();
+                }
+            }
+            CDataTypeByte::Pong { request_id } => {
+                let tx = self
+                    .session
+                    .pending
+                    .lock()
+                    .unwrap()
+                    .pings
+                    .remove(&request_id);
+
+                if let Some(tx) = tx {
+                    let _ = tx.send(());
+                }
+            }
+        }
+    }
+
+    /// Registers a connection the server told us about.
+    fn open_stream(
+        &mut self,
+        connection_id: u16,
+        peer_addr: SocketAddr,
+        is_udp: bool,
+    ) -> TunnelStream {
+        let (incoming, incoming_rx) = if is_udp {
+            mpsc::channel(INCOMING_QUEUE_SIZE)
+        } else {
+            mpsc::channel(STREAM_QUEUE_SIZE)
+        };
+        let closed = Arc::new(AtomicBool::new(false));
+        let backlog = (!is_udp).then(Arc::<Backlog>::default);
+
+        self.streams.insert(
+            connection_id,
+            Slot {
+                incoming,
+                closed: closed.clone(),
+                backlog: backlog.clone(),
+                is_udp,
+                eof: false,
+                shut_down: false,
+            },
+        );
+
+        TunnelStream {
+            connection_id,
+            peer_addr,
+            reader: StreamReader {
+                incoming: incoming_rx,
+                closed: closed.clone(),
+                flow: backlog.map(|backlog| Flow {
+                    connection_id,
+                    backlog,
+                    control: self.control.clone(),
+                }),
+            },
+            writer: StreamWriter {
+                connection_id,
+                outgoing: self.session.outgoing.clone(),
+                closed,
+                shut_down: false,
+                control: Some(self.control.clone()),
+            },
+        }
+    }
+
+    fn take_request(
+        &self,
+        request_id: u16,
+    ) -> Option<oneshot::Sender<Result<TunnelStream, ConnectError>>> {
+        self.session
+            .pending
+            .lock()
+            .unwrap()
+            .requests
+            .remove(&request_id)
+    }
+
+    /// Hands data to the local side of a connection without waiting for it,
+    /// so a slow reader can't hold up the other connections. A TCP connection
+    /// whose reader falls behind is paused on the server, and reset if it
+    /// falls too far behind anyway or is gone. Datagrams for a UDP one are
+    /// dropped instead.
+    async fn forward(&mut self, connection_id: u16, incoming: Incoming) {
+        let Entry::Occupied(mut slot) = self.streams.entry(connection_id) else {
+            return;
+        };
+
+        let eof = matches!(incoming, Incoming::Eof);
+
+        // Counted before sending, so the reader never takes away more than
+        // was added.
+        let backlog = match (&slot.get().backlog, &incoming) {
+            (Some(backlog), Incoming::Data(data)) => {
+                backlog.bytes.fetch_add(data.len(), Ordering::AcqRel) + data.len()
+            }
+            _ => 0,
+        };
+        let over_budget = backlog > MAX_BUFFERED;
+
+        let res = if over_budget {
+            Err(TrySendError::Full(incoming))
+        } else {
+            slot.get().incoming.try_send(incoming)
+        };
+
+        match res {
+            Ok(()) => {
+                if eof && slot.get().shut_down {
+                    slot.remove();
+                } else if eof {
+                    slot.get_mut().eof = true;
+                } else if backlog > PAUSE_BUFFERED
+                    && let Some(backlog) = &slot.get().backlog
+                    && !backlog.paused.swap(true, Ordering::AcqRel)
+                {
+                    let _ = self
+                        .session
+                        .outgoing
+                        .send(Outgoing::Pause { connection_id })
+                        .await;
+                }
+
+                return;
+            }
+            Err(TrySendError::Full(_)) if slot.get().is_udp => return,
+            Err(TrySendError::Full(_)) => {
+                log::debug!("Connection {connection_id} isn't read fast enough, resetting it");
+            }
+            Err(TrySendError::Closed(_)) => {}
+        }
+
+        let slot = slot.remove();
+
+        if !slot.closed.swap(true, Ordering::AcqRel) {
+            let _ = self
+                .session
+                .outgoing
+                .send(Outgoing::Reset { connection_id })
+                .await;
+        }
+    }
+
+    async fn handle_control(&mut self, control: Control) {
+        match control {
+            Control::Finished(finished) => self.finish(finished).await,
+            Control::Resume {
+                connection_id,
+                backlog,
+            } => {
+                let current = self
+                    .streams
+                    .get(&connection_id)
+                    .and_then(|slot| slot.backlog.as_ref())
+                    .is_some_and(|current| Arc::ptr_eq(current, &backlog));
+
+                if current {
+                    let _ = self
+                        .session
+                        .outgoing
+                        .send(Outgoing::Resume { connection_id })
+                        .await;
+                }
+            }
+            Control::Unbind { listener_id } => {
+                self.listeners.remove(&listener_id);
+
+                let _ = self
+                    .session
+                    .outgoing
+                    .send(Outgoing::Unbind { listener_id })
+                    .await;
+            }
+        }
+    }
+
+    /// Forgets a stream which is done sending, right away if it was dropped
+    /// and otherwise once the server is done too. A dropped stream is reset on
+    /// the server.
+    async fn finish(&mut self, finished: Finished) {
+        let connection_id = finished.connection_id;
+
+        let Entry::Occupied(mut slot) = self.streams.entry(connection_id) else {
+            return;
+        };
+
+        if !Arc::ptr_eq(&slot.get().closed, &finished.closed) {
+            return;
+        }
+
+        if finished.reset {
+            slot.remove();
+
+            let _ = self
+                .session
+                .outgoing
+                .send(Outgoing::Reset { connection_id })
+                .await;
+        } else if slot.get().eof {
+            slot.remove();
+        } else {
+            slot.get_mut().shut_down = true;
+        }
+    }
+}
+
+async fn write_loop(
+    mut writer: PacketWriteHalf,
+    mut outgoing: mpsc::Receiver<Outgoing>,
+) -> Result<()> {
+    while let Some(outgoing) = outgoing.recv().await {
+        let data_type = match &outgoing {
+            Outgoing::Connect {
+                request_id,
+                target: Target::Addr(addr),
+                is_udp,
+            } => SDataTypeByte::Connect {
+                request_id: *request_id,
+                ip: addr.ip(),
+                port: addr.port(),
+                is_udp: *is_udp,
+            },
+            Outgoing::Connect {
+                request_id,
+                target: Target::Domain(domain, port),
+                is_udp,
+            } => SDataTypeByte::ConnectDomain {
+                request_id: *request_id,
+                domain: Bounded(domain),
+                port: *port,
+                is_udp: *is_udp,
+            },
+            Outgoing::Process {
+                connection_id,
+                data,
+            } => SDataTypeByte::Process {
+                connection_id: *connection_id,
+                data,
+            },
+            Outgoing::Shutdown { connection_id } => SDataTypeByte::Shutdown {
+                connection_id: *connection_id,
+            },
+            Outgoing::Reset { connection_id } => SDataTypeByte::Reset {
+                connection_id: *connection_id,
+            },
+            Outgoing::Bind { request_id, port } => SDataTypeByte::Bind {
+                request_id: *request_id,
+                port: *port,
+            },
+            Outgoing::Unbind { listener_id } => SDataTypeByte::Unbind {
+                listener_id: *listener_id,
+            },
+            Outgoing::Ping { request_id } => SDataTypeByte::Ping {
+                request_id: *request_id,
+            },
+            Outgoing::Pause { connection_id } => SDataTypeByte::Pause {
+                connection_id: *connection_id,
+            },
+            Outgoing::Resume { connection_id } => SDataTypeByte::Resume {
+                connection_id: *connection_id,
+            },
+        };
+
+        writer
+            .send_packet(&SData { data_type })
+            .await
+            .context("failed to write to the server")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dispatcher() -> (Dispatcher, mpsc::Receiver<Outgoing>) {
+        let (outgoing, outgoing_rx) = mpsc::channel(OUTGOING_QUEUE_SIZE);
+        let (control, control_rx) = mpsc::unbounded_channel();
+
+        let dispatcher = Dispatcher {
+            session: Session {
+                outgoing,
+                pending: Arc::default(),
+            },
+            streams: HashMap::new(),
+            listeners: HashMap::new(),
+            control,
+            control_rx,
+        };
+
+        (dispatcher, outgoing_rx)
+    }
+
+    fn open(dispatcher: &mut Dispatcher, connection_id: u16, is_udp: bool) -> TunnelStream {
+        dispatcher.open_stream(connection_id, "192.0.2.1:80".parse().unwrap(), is_udp)
+    }
+
+    async fn process(dispatcher: &mut Dispatcher, connection_id: u16, data: &[u8]) {
+        dispatcher
+            .handle_packet(CDataTypeByte::Process {
+                connection_id,
+                data,
+            })
+            .await;
+    }
+
+    /// Lets the dispatcher hear from the streams and listeners which are
+    /// done.
+    async fn finish_all(dispatcher: &mut Dispatcher) {
+        while let Ok(control) = dispatcher.control_rx.try_recv() {
+            dispatcher.handle_control(control).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_reader_is_reset_alone() {
+        let (mut dispatcher, mut outgoing) = dispatcher();
+        let mut slow = open(&mut dispatcher, 1, false);
+        let mut other = open(&mut dispatcher, 2, false);
+        let chunk = vec![0; 64 << 10];
+
+        for _ in 0..MAX_BUFFERED / chunk.len() {
+            process(&mut dispatcher, 1, &chunk).await;
+        }
+        assert!(dispatcher.streams.contains_key(&1));
+
+        process(&mut dispatcher, 1, &chunk).await;
+        assert!(!dispatcher.streams.contains_key(&1));
+        assert!(matches!(
+            outgoing.try_recv(),
+            Ok(Outgoing::Pause { connection_id: 1 })
+        ));
+        assert!(matches!(
+            outgoing.try_recv(),
+            Ok(Outgoing::Reset { connection_id: 1 })
+        ));
+
+        process(&mut dispatcher, 2, b"other").await;
+        assert_eq!(other.reader.recv().await.unwrap().unwrap(), "other");
+
+        // What was queued is thrown away.
+        let e = slow.reader.recv().await.unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<io::Error>().unwrap().kind(),
+            io::ErrorKind::ConnectionReset
+        );
+
+        // The server already knows, so dropping the stream sends nothing.
+        drop(slow);
+        assert!(outgoing.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn server_reset_skips_queued_data() {
+        let (mut dispatcher, mut outgoing) = dispatcher();
+        let mut stream = open(&mut dispatcher, 1, false);
+
+        process(&mut dispatcher, 1, b"data").await;
+        dispatcher
+            .handle_packet(CDataTypeByte::Reset {
+                connection_id: 1,
+                reason: ResetReason::TimedOut,
+            })
+            .await;
+
+        let e = stream.reader.recv().await.unwrap_err();
+        assert_eq!(e.to_string(), "connection reset by server: TimedOut");
+
+        drop(stream);
+        assert!(outgoing.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn reader_keeping_up_is_not_reset() {
+        let (mut dispatcher, _outgoing) = dispatcher();
+        let mut stream = open(&mut dispatcher, 1, false);
+        let chunk = vec![0; 64 << 10];
+
+        for _ in 0..MAX_BUFFERED / chunk.len() * 2 {
+            process(&mut dispatcher, 1, &chunk).await;
+            stream.reader.recv().await.unwrap().unwrap();
+        }
+
+        assert!(dispatcher.streams.contains_key(&1));
+    }
+
+    #[tokio::test]
+    async fn paused_until_the_reader_caught_up() {
+        let (mut dispatcher, mut outgoing) = dispatcher();
+        let mut stream = open(&mut dispatcher, 1, false);
+        let chunk = vec![0; 64 << 10];
+        let chunks = PAUSE_BUFFERED / chunk.len() + 4;
+
+        for _ in 0..chunks {
+            process(&mut dispatcher, 1, &chunk).await;
+        }
+
+        // Asked once, however much more arrives.
+        assert!(matches!(
+            outgoing.try_recv(),
+            Ok(Outgoing::Pause { connection_id: 1 })
+        ));
+        assert!(outgoing.try_recv().is_err());
+
+        let mut left = chunks * chunk.len();
+
+        while left > RESUME_BUFFERED {
+            stream.reader.recv().await.unwrap().unwrap();
+            left -= chunk.len();
+
+            finish_all(&mut dispatcher).await;
+            assert_eq!(outgoing.try_recv().is_ok(), left <= RESUME_BUFFERED);
+        }
+
+        // A resume from a stream which is gone doesn't reach the new one.
+        process(&mut dispatcher, 1, &chunk).await;
+        let (reader, _writer) = stream.into_split();
+        let backlog = reader.flow.as_ref().unwrap().backlog.clone();
+        dispatcher.streams.clear();
+        let _new = open(&mut dispatcher, 1, false);
+        dispatcher
+            .handle_control(Control::Resume {
+                connection_id: 1,
+                backlog,
+            })
+            .await;
+        assert!(outgoing.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn tiny_chunks_are_limited_too() {
+        let (mut dispatcher, mut outgoing) = dispatcher();
+        let _stream = open(&mut dispatcher, 1, false);
+
+        for _ in 0..=STREAM_QUEUE_SIZE {
+            process(&mut dispatcher, 1, b"x").await;
+        }
+
+        assert!(!dispatcher.streams.contains_key(&1));
+        assert!(matches!(
+            outgoing.try_recv(),
+            Ok(Outgoing::Reset { connection_id: 1 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn udp_drops_datagrams() {
+        let (mut dispatcher, mut outgoing) = dispatcher();
+        let mut stream = open(&mut dispatcher, 1, true);
+
+        for _ in 0..INCOMING_QUEUE_SIZE * 2 {
+            process(&mut dispatcher, 1, b"datagram").await;
+        }
+
+        assert!(dispatcher.streams.contains_key(&1));
+        assert!(outgoing.try_recv().is_err());
+
+        for _ in 0..INCOMING_QUEUE_SIZE {
+            stream.reader.recv().await.unwrap().unwrap();
+        }
+
+        process(&mut dispatcher, 1, b"later").await;
+        assert_eq!(stream.reader.recv().await.unwrap().unwrap(), "later");
+    }
+
+    #[tokio::test]
+    async fn forgotten_once_both_sides_finished() {
+        let (mut dispatcher, _outgoing) = dispatcher();
+
+        // The server finishes first.
+        let mut stream = open(&mut dispatcher, 1, false);
+        dispatcher
+            .handle_packet(CDataTypeByte::Shutdown { connection_id: 1 })
+            .await;
+        assert!(dispatcher.streams.contains_key(&1));
+        assert!(stream.reader.recv().await.unwrap().is_none());
+
+        stream.writer.shutdown().await.unwrap();
+        finish_all(&mut dispatcher).await;
+        assert!(!dispatcher.streams.contains_key(&1));
+
+        // The local side finishes first.
+        let mut stream = open(&mut dispatcher, 2, false);
+        stream.writer.shutdown().await.unwrap();
+        finish_all(&mut dispatcher).await;
+        assert!(dispatcher.streams.contains_key(&2));
+
+        dispatcher
+            .handle_packet(CDataTypeByte::Shutdown { connection_id: 2 })
+            .await;
+        assert!(!dispatcher.streams.contains_key(&2));
+        assert!(stream.reader.recv().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn forgotten_when_writer_is_dropped() {
+        let (mut dispatcher, mut outgoing) = dispatcher();
+
+        let stream = open(&mut dispatcher, 1, false);
+        dispatcher
+            .handle_packet(CDataTypeByte::Shutdown { connection_id: 1 })
+            .await;
+        drop(stream);
+        finish_all(&mut dispatcher).await;
+
+        assert!(dispatcher.streams.is_empty());
+        assert!(matches!(
+            outgoing.try_recv(),
+            Ok(Outgoing::Reset { connection_id: 1 })
+        ));
+
+        // A late message about an old stream leaves a new one with its id
+        // alone.
+        let old = open(&mut dispatcher, 2, false);
+        let (_, old_writer) = old.into_split();
+        let _new = open(&mut dispatcher, 2, false);
+        drop(old_writer);
+        finish_all(&mut dispatcher).await;
+
+        assert!(dispatcher.streams.contains_key(&2));
+    }
+
+    #[tokio::test]
+    async fn dropped_writer_is_reset_once_the_queue_has_room() {
+        let (mut dispatcher, mut outgoing) = dispatcher();
+        let stream = open(&mut dispatcher, 1, false);
+
+        for request_id in 0..OUTGOING_QUEUE_SIZE as u16 {
+            let ping = Outgoing::Ping { request_id };
+            assert!(dispatcher.session.outgoing.try_send(ping).is_ok());
+        }
+
+        drop(stream);
+
+        for _ in 0..OUTGOING_QUEUE_SIZE {
+            assert!(matches!(outgoing.try_recv(), Ok(Outgoing::Ping { .. })));
+        }
+        assert!(outgoing.try_recv().is_err());
+
+        finish_all(&mut dispatcher).await;
+        assert!(matches!(
+            outgoing.try_recv(),
+            Ok(Outgoing::Reset { connection_id: 1 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn dropped_listener_is_unbound() {
+        let (mut dispatcher, mut outgoing) = dispatcher();
+        let (tx, rx) = oneshot::channel();
+        dispatcher
+            .session
+            .pending
+            .lock()
+            .unwrap()
+            .binds
+            .insert(0, tx);
+
+        dispatcher
+            .handle_packet(CDataTypeByte::Bound {
+                request_id: 0,
+                listener_id: 3,
+                port: 25565,
+            })
+            .await;
+        let listener = rx.await.unwrap().unwrap();
+
+        for request_id in 0..OUTGOING_QUEUE_SIZE as u16 {
+            let ping = Outgoing::Ping { request_id };
+            assert!(dispatcher.session.outgoing.try_send(ping).is_ok());
+        }
+
+        drop(listener);
+
+        for _ in 0..OUTGOING_QUEUE_SIZE {
+            assert!(matches!(outgoing.try_recv(), Ok(Outgoing::Ping { .. })));
+        }
+
+        finish_all(&mut dispatcher).await;
+        assert!(dispatcher.listeners.is_empty());
+        assert!(matches!(
+            outgoing.try_recv(),
+            Ok(Outgoing::Unbind { listener_id: 3 })
+        ));
+    }
+}
@@ -3,8 +3,6 @@ use std::fmt::Display;
 
 use derive_more::{AsRef, Deref, DerefMut, From};
 
-const DEFAULT_MAX_STRING_CHARS: usize = 32767;
-
 /// A newtype wrapper for `T` which modifies the [`Encode`](crate::Encode) and
 /// [`Decode`](crate::Decode) impls to be bounded by some upper limit `MAX`.
 /// Implementations are expected to error eagerly if the limit is exceeded.
@@ -1,6 +1,6 @@
 use std::io::Write;
 
-use crate::{Bounded, Encode, Packet, PacketState, VarInt};
+use crate::{Bounded, Decode, Encode, Packet, PacketState, VarInt};
 use uuid::Uuid;
 
 #[derive(Clone, Debug, Packet)]
@@ -20,3 +20,19 @@ impl Encode for CLoginFinished<'_> {
         Ok(())
     }
 }
+
+impl<'a> Decode<'a> for CLoginFinished<'a> {
+    fn decode(r: &mut &'a [u8]) -> anyhow::Result<Self> {
+        let uuid = Uuid::decode(r)?;
+        let username = Bounded::decode(r)?;
+
+        // Skin properties, which are never sent by our server.
+        for _ in 0..VarInt::decode(r)?.0 {
+            <&str>::decode(r)?;
+            <&str>::decode(r)?;
+            <Option<&str>>::decode(r)?;
+        }
+
+        Ok(Self { uuid, username })
+    }
+}
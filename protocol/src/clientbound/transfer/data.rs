@@ -1,4 +1,4 @@
-use std::net::IpAddr;
+use std::{io::ErrorKind, net::IpAddr};
 
 use crate::{Decode, Encode, Packet, PacketState};
 
@@ -11,6 +11,7 @@ pub struct CData<'a> {
 #[derive(Clone, Copy, Debug, Encode, Decode)]
 pub enum CDataTypeByte<'a> {
     Connect {
+        request_id: u16,
         ip: IpAddr,
         port: u16,
         is_udp: bool,
@@ -20,4 +21,120 @@ pub enum CDataTypeByte<'a> {
         connection_id: u16,
         data: &'a [u8],
     },
+    /// Answers a connect request which couldn't be fulfilled.
+    ConnectFailed {
+        request_id: u16,
+        reason: ConnectFailReason,
+    },
+    /// The remote host won't send any more data, but the connection can still
+    /// be written to until the client shuts it down too.
+    Shutdown {
+        connection_id: u16,
+    },
+    /// The connection is closed in both directions and its id is no longer
+    /// valid.
+    Reset {
+        connection_id: u16,
+        reason: ResetReason,
+    },
+    /// Answers a bind request, the server now listens on `port`.
+    Bound {
+        request_id: u16,
+        listener_id: u16,
+        port: u16,
+    },
+    /// Answers a bind request which couldn't be fulfilled.
+    BindFailed {
+        request_id: u16,
+        reason: BindFailReason,
+    },
+    /// A listener accepted a connection from `ip` and `port`. It is used like
+    /// a connection opened by the client.
+    Inbound {
+        listener_id: u16,
+        connection_id: u16,
+        ip: IpAddr,
+        port: u16,
+    },
+    /// Answers a ping.
+    Pong {
+        request_id: u16,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Encode, Decode)]
+pub enum ConnectFailReason {
+    /// The remote host refused the connection.
+    Refused,
+    /// There is no route to the remote host.
+    Unreachable,
+    /// The connect attempt took longer than the server allows.
+    TimedOut,
+    /// Any other I/O error on the server side.
+    Other,
+    /// The server doesn't allow the user to connect to this target.
+    Forbidden,
+    /// The server is shutting down and takes no new connections.
+    ShuttingDown,
+    /// The domain has no addresses or couldn't be looked up.
+    Unresolved,
+}
+
+impl From<ErrorKind> for ConnectFailReason {
+    fn from(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::ConnectionRefused => Self::Refused,
+            ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable => Self::Unreachable,
+            ErrorKind::TimedOut => Self::TimedOut,
+            _ => Self::Other,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Encode, Decode)]
+pub enum ResetReason {
+    /// The remote host reset or aborted the connection.
+    ConnectionReset,
+    /// The remote host stopped responding.
+    TimedOut,
+    /// No datagrams passed through a UDP connection for too long.
+    Idle,
+    /// Any other I/O error on the server side.
+    Other,
+    /// The server shut down before the connection was closed.
+    Shutdown,
+    /// The client sent data faster than the remote host accepted it.
+    Congested,
+}
+
+impl From<ErrorKind> for ResetReason {
+    fn from(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe => {
+                Self::ConnectionReset
+            }
+            ErrorKind::TimedOut => Self::TimedOut,
+            _ => Self::Other,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Encode, Decode)]
+pub enum BindFailReason {
+    /// The user isn't allowed to listen on this port.
+    Forbidden,
+    /// Something else is already listening on this port.
+    AddrInUse,
+    /// Any other I/O error on the server side.
+    Other,
+}
+
+impl From<ErrorKind> for BindFailReason {
+    fn from(kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::AddrInUse => Self::AddrInUse,
+            ErrorKind::PermissionDenied => Self::Forbidden,
+            _ => Self::Other,
+        }
+    }
 }
@@ -1,5 +1,5 @@
 use aes::cipher::{BlockDecryptMut, BlockSizeUser, KeyIvInit, generic_array::GenericArray};
-use anyhow::{Context, bail, ensure};
+use anyhow::{Context, anyhow, bail, ensure};
 use bytes::{Buf, BytesMut};
 
 use crate::varint::{VarInt, VarIntDecodeError};
@@ -9,7 +9,6 @@ use crate::{Decode, MAX_PACKET_SIZE, Packet};
 
 /// The AES block cipher with a 128 bit key, using the CFB-8 mode of
 /// operation.
-
 type Cipher = cfb8::Decryptor<aes::Aes128>;
 
 #[derive(Default)]
@@ -147,7 +146,6 @@ impl PacketDecoder {
 
     /// Decrypts the provided byte slice in place using the cipher, without
     /// consuming the cipher.
-
     fn decrypt_bytes(cipher: &mut Cipher, bytes: &mut [u8]) {
         for chunk in bytes.chunks_mut(Cipher::block_size()) {
             let gen_arr = GenericArray::from_mut_slice(chunk);
@@ -176,6 +174,11 @@ impl PacketDecoder {
         }
     }
 
+    /// Whether no bytes of an unfinished packet are queued.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
     pub fn take_capacity(&mut self) -> BytesMut {
         self.buf.split_off(self.buf.len())
     }
@@ -211,7 +214,10 @@ impl PacketFrame {
 
         let mut r = &self.body[..];
 
-        let pkt = P::decode(&mut r)?;
+        // Short bodies fail with I/O errors from reading the slice, which
+        // would look like the connection broke.
+        let pkt =
+            P::decode(&mut r).map_err(|e| anyhow!("failed to decode '{}': {e:#}", P::NAME))?;
 
         ensure!(
             r.is_empty(),
@@ -166,7 +166,6 @@ impl PacketEncoder {
     /// # Panics
     ///
     /// Panics if encryption is already enabled.
-
     pub fn enable_encryption(&mut self, key: &[u8; 16]) {
         assert!(self.cipher.is_none(), "encryption is already enabled");
         self.cipher = Some(Cipher::new_from_slices(key, key).expect("invalid key"));
@@ -233,12 +232,10 @@ impl WritePacket for PacketWriter<'_> {
     {
         let start = self.buf.len();
 
-        let res;
-
-        if self.threshold.0 >= 0 {
-            res = encode_packet_compressed(self.buf, pkt, self.threshold.0 as u32);
+        let res = if self.threshold.0 >= 0 {
+            encode_packet_compressed(self.buf, pkt, self.threshold.0 as u32)
         } else {
-            res = encode_packet(self.buf, pkt)
+            encode_packet(self.buf, pkt)
         };
 
         if res.is_err() {
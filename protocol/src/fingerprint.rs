@@ -0,0 +1,16 @@
+use std::fmt::Write;
+
+use sha2::{Digest, Sha256};
+
+/// Fingerprint of a server's public key, as sent in the encryption request.
+///
+/// This is the lowercase hex SHA-256 of the DER encoded key, which is short
+/// enough to compare by eye and to put into a config file.
+pub fn key_fingerprint(public_key: &[u8]) -> String {
+    Sha256::digest(public_key)
+        .iter()
+        .fold(String::with_capacity(64), |mut out, byte| {
+            let _ = write!(out, "{byte:02x}");
+            out
+        })
+}
@@ -20,6 +20,7 @@ use derive_more::{From, Into};
 
 pub mod bounded;
 pub mod clientbound;
+pub mod fingerprint;
 pub mod impls;
 pub mod packet_io;
 pub mod serverbound;
@@ -29,6 +30,7 @@ pub mod decode;
 pub mod encode;
 
 pub use bounded::Bounded;
+pub use fingerprint::key_fingerprint;
 pub use protocol_macros::{Decode, Encode, Packet};
 pub use varint::VarInt;
 
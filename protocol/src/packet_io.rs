@@ -1,9 +1,13 @@
 use std::io::{self, ErrorKind};
 
+use anyhow::bail;
 use bytes::BytesMut;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    net::{
+        TcpStream,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
 };
 
 use crate::{
@@ -13,23 +17,41 @@ use crate::{
 };
 
 pub struct PacketIo {
-    stream: TcpStream,
-    enc: PacketEncoder,
+    reader: PacketReadHalf,
+    writer: PacketWriteHalf,
+}
+
+/// The receiving half of a [`PacketIo`], created by [`PacketIo::into_split`].
+pub struct PacketReadHalf {
+    stream: OwnedReadHalf,
     dec: PacketDecoder,
     frame: PacketFrame,
 }
 
+/// The sending half of a [`PacketIo`], created by [`PacketIo::into_split`].
+pub struct PacketWriteHalf {
+    stream: OwnedWriteHalf,
+    enc: PacketEncoder,
+}
+
 const READ_BUF_SIZE: usize = 4096;
 
 impl PacketIo {
     pub fn new(stream: TcpStream) -> Self {
+        let (read, write) = stream.into_split();
+
         Self {
-            stream,
-            enc: PacketEncoder::new(),
-            dec: PacketDecoder::new(),
-            frame: PacketFrame {
-                id: -1,
-                body: BytesMut::new(),
+            reader: PacketReadHalf {
+                stream: read,
+                dec: PacketDecoder::new(),
+                frame: PacketFrame {
+                    id: -1,
+                    body: BytesMut::new(),
+                },
+            },
+            writer: PacketWriteHalf {
+                stream: write,
+                enc: PacketEncoder::new(),
             },
         }
     }
@@ -38,28 +60,70 @@ impl PacketIo {
     where
         P: Packet + Encode,
     {
-        self.enc.append_packet(pkt)?;
-        let bytes = self.enc.take();
-        self.stream.write_all(&bytes).await?;
-        Ok(())
+        self.writer.send_packet(pkt).await
     }
 
     pub async fn recv_packet<'a, P>(&'a mut self) -> anyhow::Result<P>
     where
         P: Packet + Decode<'a>,
     {
+        self.reader.recv_packet().await
+    }
+
+    pub async fn recv_frame(&mut self) -> anyhow::Result<&PacketFrame> {
+        self.reader.recv_frame().await
+    }
+
+    pub fn set_compression(&mut self, threshold: CompressionThreshold) {
+        self.writer.enc.set_compression(threshold);
+        self.reader.dec.set_compression(threshold);
+    }
+
+    pub fn enable_encryption(&mut self, key: &[u8; 16]) {
+        self.writer.enc.enable_encryption(key);
+        self.reader.dec.enable_encryption(key);
+    }
+
+    /// Splits the connection into halves which can be used concurrently,
+    /// keeping the compression and encryption state of both directions.
+    pub fn into_split(self) -> (PacketReadHalf, PacketWriteHalf) {
+        (self.reader, self.writer)
+    }
+}
+
+impl PacketReadHalf {
+    pub async fn recv_packet<'a, P>(&'a mut self) -> anyhow::Result<P>
+    where
+        P: Packet + Decode<'a>,
+    {
+        self.recv_frame().await?.decode()
+    }
+
+    /// Receives the next packet without decoding it, for when several packets
+    /// may arrive and the caller has to look at the ID first.
+    ///
+    /// A connection closed between packets fails with an [`io::Error`] of
+    /// kind [`ErrorKind::UnexpectedEof`], one closed in the middle of a packet
+    /// with a plain error.
+    pub async fn recv_frame(&mut self) -> anyhow::Result<&PacketFrame> {
         loop {
             if let Some(frame) = self.dec.try_next_packet()? {
                 self.frame = frame;
 
-                return self.frame.decode();
+                return Ok(&self.frame);
             }
 
             self.dec.reserve(READ_BUF_SIZE);
             let mut buf = self.dec.take_capacity();
 
             if self.stream.read_buf(&mut buf).await? == 0 {
-                return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+                // Only a connection closed between packets was closed on
+                // purpose.
+                if self.dec.is_empty() {
+                    return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+                }
+
+                bail!("connection closed in the middle of a packet");
             }
 
             // This should always be an O(1) unsplit because we reserved space earlier and
@@ -67,14 +131,16 @@ impl PacketIo {
             self.dec.queue_bytes(buf);
         }
     }
+}
 
-    pub fn set_compression(&mut self, threshold: CompressionThreshold) {
-        self.enc.set_compression(threshold);
-        self.dec.set_compression(threshold);
-    }
-
-    pub fn enable_encryption(&mut self, key: &[u8; 16]) {
-        self.enc.enable_encryption(key);
-        self.dec.enable_encryption(key);
+impl PacketWriteHalf {
+    pub async fn send_packet<P>(&mut self, pkt: &P) -> anyhow::Result<()>
+    where
+        P: Packet + Encode,
+    {
+        self.enc.append_packet(pkt)?;
+        let bytes = self.enc.take();
+        self.stream.write_all(&bytes).await?;
+        Ok(())
     }
 }
@@ -1,6 +1,6 @@
 use std::net::IpAddr;
 
-use crate::{Decode, Encode, Packet, PacketState};
+use crate::{Bounded, Decode, Encode, Packet, PacketState};
 
 #[derive(Clone, Copy, Debug, Encode, Decode, Packet)]
 #[packet(state = PacketState::Config, name = "custom_payload")]
@@ -10,7 +10,56 @@ pub struct SData<'a> {
 
 #[derive(Clone, Copy, Debug, Encode, Decode)]
 pub enum SDataTypeByte<'a> {
-    Connect { ip: IpAddr, port: u16, is_udp: bool },
-    Process { connection_id: u16, data: &'a [u8] },
-    Shutdown { connection_id: u16 },
+    /// Asks the server to open a connection. The answer carries the same
+    /// `request_id`, so several connects can be in flight at once.
+    Connect {
+        request_id: u16,
+        ip: IpAddr,
+        port: u16,
+        is_udp: bool,
+    },
+    Process {
+        connection_id: u16,
+        data: &'a [u8],
+    },
+    /// The client won't send any more data, but still reads the responses.
+    Shutdown {
+        connection_id: u16,
+    },
+    /// Closes the connection in both directions.
+    Reset {
+        connection_id: u16,
+    },
+    /// Asks the server to listen on `port`. Connections accepted there are
+    /// announced with the same `listener_id` as in the answer.
+    Bind {
+        request_id: u16,
+        port: u16,
+    },
+    /// Stops listening, connections which were already accepted stay open.
+    Unbind {
+        listener_id: u16,
+    },
+    /// Asks the server to answer with a pong, which shows that the session
+    /// is alive and accepted.
+    Ping {
+        request_id: u16,
+    },
+    /// Like [`SDataTypeByte::Connect`], but the server resolves the domain
+    /// itself and answers with the address it actually connected to.
+    ConnectDomain {
+        request_id: u16,
+        domain: Bounded<&'a str, 255>,
+        port: u16,
+        is_udp: bool,
+    },
+    /// Asks the server to stop reading from the remote host of a connection,
+    /// as the data it already sent is still waiting to be read locally.
+    Pause {
+        connection_id: u16,
+    },
+    /// Lets the server read from the remote host again after a pause.
+    Resume {
+        connection_id: u16,
+    },
 }
@@ -0,0 +1,389 @@
+use std::{
+    collections::HashSet,
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, ensure};
+use protocol::MAX_PACKET_SIZE;
+use serde::{Deserialize, Deserializer, de};
+use uuid::Uuid;
+
+/// Everything the server reads from its config file at startup.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Addresses to accept clients on.
+    #[serde(default = "default_bind")]
+    pub bind: Vec<SocketAddr>,
+    /// Packets at least this large are compressed. Negative values disable
+    /// compression.
+    #[serde(default = "default_compression_threshold")]
+    pub compression_threshold: i32,
+    /// PKCS#8 PEM file with the server's private key. It is generated on the
+    /// first start, clients pin it afterwards.
+    #[serde(default = "default_key_path")]
+    pub key_path: PathBuf,
+    /// Size of newly generated keys in bits.
+    #[serde(default = "default_key_size")]
+    pub key_size: usize,
+    /// Where listeners requested by clients for reverse forwarding are opened.
+    #[serde(default = "default_reverse_listen_ip")]
+    pub reverse_listen_ip: IpAddr,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub status: Status,
+    #[serde(default)]
+    pub shutdown: Shutdown,
+    #[serde(default)]
+    pub limits: Limits,
+    /// Users which are allowed to log in.
+    #[serde(default, rename = "user")]
+    pub users: Vec<User>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Timeouts {
+    /// How long connecting to a remote host may take.
+    #[serde(
+        rename = "connect_secs",
+        default = "default_connect_timeout",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub connect: Duration,
+    /// How long a UDP connection may stay without traffic.
+    #[serde(
+        rename = "udp_idle_secs",
+        default = "default_udp_idle_timeout",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub udp_idle: Duration,
+    /// How long a new client may take to say whether it wants the status or
+    /// to log in.
+    #[serde(
+        rename = "handshake_secs",
+        default = "default_handshake_timeout",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub handshake: Duration,
+    /// How long answering a status request may take.
+    #[serde(
+        rename = "status_secs",
+        default = "default_status_timeout",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub status: Duration,
+    /// How long logging in may take, including the encryption handshake.
+    #[serde(
+        rename = "login_secs",
+        default = "default_login_timeout",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub login: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: default_connect_timeout(),
+            udp_idle: default_udp_idle_timeout(),
+            handshake: default_handshake_timeout(),
+            status: default_status_timeout(),
+            login: default_login_timeout(),
+        }
+    }
+}
+
+/// Protects the server against floods of clients which never log in.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Limits {
+    /// How many clients may be connected without having logged in yet, more
+    /// are dropped right away.
+    pub max_unauthenticated: usize,
+    /// Clients from the same network share their rate limits. This is the
+    /// prefix length of those networks for IPv4 addresses.
+    pub ipv4_prefix: u8,
+    /// The same for IPv6, where a single host usually gets a whole /64.
+    pub ipv6_prefix: u8,
+    /// Status requests per network.
+    pub status: RateLimit,
+    /// Login attempts per network.
+    pub login: RateLimit,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_unauthenticated: 512,
+            ipv4_prefix: 32,
+            ipv6_prefix: 64,
+            status: RateLimit {
+                burst: 20,
+                per_minute: 60,
+            },
+            login: RateLimit {
+                burst: 10,
+                per_minute: 20,
+            },
+        }
+    }
+}
+
+/// A token bucket: `burst` requests at once, refilled by `per_minute`.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub burst: u32,
+    /// 0 disables the limit.
+    pub per_minute: u32,
+}
+
+/// What happens to connected clients when the server is stopped.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Shutdown {
+    /// How long logged in clients may keep their open connections before
+    /// they are closed.
+    #[serde(rename = "drain_secs", deserialize_with = "deserialize_secs")]
+    pub drain_timeout: Duration,
+    /// Shown to clients when they are disconnected.
+    pub message: String,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            drain_timeout: Duration::from_secs(30),
+            message: "Server closed".to_string(),
+        }
+    }
+}
+
+/// What the server shows in the multiplayer server list.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Status {
+    /// Whether status requests are answered at all.
+    pub enabled: bool,
+    pub version_name: String,
+    pub description: String,
+    pub online_players: i32,
+    pub max_players: i32,
+    /// 64x64 PNG image.
+    pub favicon: Option<PathBuf>,
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            version_name: "1.21.5".to_string(),
+            description: "A Minecraft Server".to_string(),
+            online_players: 0,
+            max_players: 20,
+            favicon: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct User {
+    pub name: String,
+    /// Sent before the connection is encrypted, so it only tells users apart.
+    pub public_uuid: Uuid,
+    /// Sent once the connection is encrypted and proves who the user is.
+    pub private_uuid: Uuid,
+    /// Ports the user may ask the server to listen on.
+    #[serde(default)]
+    pub bind_ports: Vec<PortRange>,
+}
+
+/// Either a single port like `8080` or a range like `"8000-8100"`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Port(u16),
+            Range(String),
+        }
+
+        let (start, end) = match Repr::deserialize(deserializer)? {
+            Repr::Port(port) => (port, port),
+            Repr::Range(range) => {
+                let parsed = range.split_once('-').and_then(|(start, end)| {
+                    Some((start.trim().parse().ok()?, end.trim().parse().ok()?))
+                });
+
+                parsed.ok_or_else(|| {
+                    de::Error::custom(format!("expected a port or `start-end`, got `{range}`"))
+                })?
+            }
+        };
+
+        if start > end {
+            return Err(de::Error::custom(format!(
+                "port range {start}-{end} is empty"
+            )));
+        }
+
+        Ok(Self { start, end })
+    }
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read config {}", path.display()))?;
+
+        let config: Self = toml::from_str(&text)
+            .with_context(|| format!("failed to parse config {}", path.display()))?;
+
+        config
+            .validate()
+            .with_context(|| format!("invalid config {}", path.display()))?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        ensure!(!self.bind.is_empty(), "`bind`: no addresses to listen on");
+
+        ensure!(
+            self.compression_threshold < MAX_PACKET_SIZE,
+            "`compression_threshold`: must be less than {MAX_PACKET_SIZE}"
+        );
+
+        ensure!(
+            (1024..=8192).contains(&self.key_size),
+            "`key_size`: must be between 1024 and 8192 bits"
+        );
+
+        ensure!(
+            !self.timeouts.connect.is_zero(),
+            "`timeouts.connect_secs`: must be greater than zero"
+        );
+        ensure!(
+            !self.timeouts.udp_idle.is_zero(),
+            "`timeouts.udp_idle_secs`: must be greater than zero"
+        );
+        ensure!(
+            !self.timeouts.handshake.is_zero(),
+            "`timeouts.handshake_secs`: must be greater than zero"
+        );
+        ensure!(
+            !self.timeouts.status.is_zero(),
+            "`timeouts.status_secs`: must be greater than zero"
+        );
+        ensure!(
+            !self.timeouts.login.is_zero(),
+            "`timeouts.login_secs`: must be greater than zero"
+        );
+
+        ensure!(
+            self.limits.max_unauthenticated > 0,
+            "`limits.max_unauthenticated`: must be greater than zero"
+        );
+        ensure!(
+            self.limits.ipv4_prefix <= 32,
+            "`limits.ipv4_prefix`: must be at most 32"
+        );
+        ensure!(
+            self.limits.ipv6_prefix <= 128,
+            "`limits.ipv6_prefix`: must be at most 128"
+        );
+        ensure!(
+            self.limits.status.burst > 0,
+            "`limits.status.burst`: must be greater than zero"
+        );
+        ensure!(
+            self.limits.login.burst > 0,
+            "`limits.login.burst`: must be greater than zero"
+        );
+
+        let mut names = HashSet::new();
+
+        for (i, user) in self.users.iter().enumerate() {
+            ensure!(
+                (1..=16).contains(&user.name.chars().count()),
+                "`user[{i}].name`: must be 1 to 16 characters long"
+            );
+            ensure!(
+                names.insert(user.name.as_str()),
+                "`user[{i}].name`: `{}` is already used by another user",
+                user.name
+            );
+            ensure!(
+                user.public_uuid != user.private_uuid,
+                "`user[{i}].private_uuid`: must differ from `public_uuid`, which is sent \
+                 unencrypted"
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn default_bind() -> Vec<SocketAddr> {
+    vec![SocketAddr::from(([0, 0, 0, 0], 25565))]
+}
+
+pub(crate) fn default_reverse_listen_ip() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+}
+
+pub(crate) fn default_compression_threshold() -> i32 {
+    256
+}
+
+fn default_key_path() -> PathBuf {
+    PathBuf::from("rkp-server.pem")
+}
+
+fn default_key_size() -> usize {
+    2048
+}
+
+fn default_connect_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_udp_idle_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_handshake_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_status_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_login_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn deserialize_secs<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
@@ -1,19 +1,20 @@
 use std::{
-    collections::HashMap,
-    net::{IpAddr, SocketAddr},
+    fmt,
+    io::{self, ErrorKind},
+    net::SocketAddr,
     sync::Arc,
+    time::Duration,
 };
 
-use anyhow::{Result, anyhow, bail, ensure};
+use anyhow::anyhow;
 use protocol::{
-    Bounded,
+    Bounded, VarInt,
     clientbound::{
         login::{
-            encryption_request::CEncryptionRequest, login_disconnect::CLoginDisconnect,
-            login_success::CLoginFinished,
+            encryption_request::CEncryptionRequest, login_compression::CLoginCompression,
+            login_disconnect::CLoginDisconnect, login_success::CLoginFinished,
         },
         status::{ping_response::CPongResponse, status_response::CStatusResponse},
-        transfer::data::{CData, CDataTypeByte},
     },
     packet_id::CURRENT_MC_PROTOCOL,
     packet_io::PacketIo,
@@ -24,69 +25,244 @@ use protocol::{
             login_acknowledged::SLoginAcknowledged,
         },
         status::{ping_request::SPingRequest, status_request::SStatusRequest},
-        transfer::{
-            client_information::SClientInformation,
-            data::{SData, SDataTypeByte},
-        },
+        transfer::client_information::SClientInformation,
     },
 };
 use rsa::Pkcs1v15Encrypt;
-use tokio::{
-    io::AsyncWriteExt,
-    net::{TcpStream, UdpSocket},
-};
+use tokio::{net::TcpStream, sync::OwnedSemaphorePermit, time};
 use valence_text::{Color, IntoText};
 
-use crate::server::Server;
+use crate::{
+    config::User,
+    handler::{Handler, Session},
+    ping::ServerListPing,
+    relay::Relay,
+    server::Server,
+};
 
-pub struct Client {
+pub struct Client<H> {
     io: PacketIo,
     remote_addr: SocketAddr,
-    server: Arc<Server>,
+    server: Arc<Server<H>>,
+    phase: Phase,
+    /// Counts the client against the limit of unauthenticated ones until it
+    /// is logged in.
+    permit: Option<OwnedSemaphorePermit>,
 
     info: Option<SClientInformation>,
-    next_connection_id: u16,
-    connections: HashMap<u16, Connection>,
 }
 
-impl Client {
-    pub fn new(stream: TcpStream, remote_addr: SocketAddr, server: Arc<Server>) -> Result<Self> {
+/// Why a client connection ended.
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    /// The client sent something which doesn't fit the protocol.
+    #[error("protocol violation: {0:#}")]
+    Protocol(anyhow::Error),
+    /// The client isn't who it claims to be, or isn't allowed in at all.
+    #[error("authentication failed: {0}")]
+    Auth(String),
+    /// The network of the client sent too many requests recently.
+    #[error("rate limited")]
+    RateLimited,
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Sorts the errors of the packet layer, which keeps I/O errors as they are.
+impl From<anyhow::Error> for ClientError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast() {
+            Ok(e) => Self::Io(e),
+            Err(e) => Self::Protocol(e),
+        }
+    }
+}
+
+/// How far a client connection got.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Waiting for the handshake, which says whether a status or a login
+    /// follows.
+    Handshake,
+    Status,
+    /// Logging in, up to and including the second check by private UUID.
+    Login,
+    /// Logged in and relaying connections.
+    Relay,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Handshake => "handshake",
+            Self::Status => "status",
+            Self::Login => "login",
+            Self::Relay => "relay",
+        })
+    }
+}
+
+impl<H: Handler> Client<H> {
+    pub fn new(
+        stream: TcpStream,
+        remote_addr: SocketAddr,
+        server: Arc<Server<H>>,
+        permit: OwnedSemaphorePermit,
+    ) -> io::Result<Self> {
         stream.set_nodelay(true)?;
 
         Ok(Self {
             io: PacketIo::new(stream),
             remote_addr,
             server,
+            phase: Phase::Handshake,
+            permit: Some(permit),
 
             info: None,
-            next_connection_id: 0,
-            connections: HashMap::new(),
         })
     }
 
-    pub async fn handle(mut self) -> Result<()> {
-        let SIntention {
-            next_state,
-            protocol_version,
-            ..
-        } = self.io.recv_packet().await?;
+    /// Serves the client until it leaves, logging why it did.
+    pub async fn handle(mut self) {
+        let remote_addr = self.remote_addr;
+        let shutting_down = self.server.shutting_down.clone();
+
+        let negotiated = tokio::select! {
+            res = self.negotiate() => res,
+            _ = shutting_down.cancelled() => {
+                self.disconnect_for_shutdown().await;
+                return;
+            }
+        };
+
+        let (phase, res) = match negotiated {
+            Ok(Some(user)) => (Phase::Relay, self.relay(user).await),
+            Ok(None) => return,
+            Err(e) => (self.phase, Err(e)),
+        };
+
+        match res {
+            Ok(()) => {}
+            Err(ClientError::Io(e)) => {
+                log::info!("Connection with {remote_addr} ended during {phase}: {e}")
+            }
+            // Logged quietly, as floods are what triggers it.
+            Err(ClientError::RateLimited) => {
+                log::debug!("Dropped {remote_addr} during {phase}: rate limited")
+            }
+            Err(e) => log::warn!("Dropped {remote_addr} during {phase}: {e}"),
+        }
+    }
+
+    /// Answers a status request, or logs the client in and returns its user.
+    async fn negotiate(&mut self) -> Result<Option<User>, ClientError> {
+        let (next_state, ver) = deadline(self.server.handshake_timeout, async {
+            let SIntention {
+                next_state,
+                protocol_version,
+                ..
+            } = self.io.recv_packet().await?;
+
+            Ok((next_state, protocol_version.0))
+        })
+        .await?;
+
+        let ip = self.remote_addr.ip();
 
         match next_state {
-            HandshakeNextState::Status => self.handle_status(protocol_version.0).await?,
+            HandshakeNextState::Status => {
+                self.phase = Phase::Status;
+
+                if !self.server.status_limiter.check(ip) {
+                    return Err(ClientError::RateLimited);
+                }
+
+                deadline(self.server.status_timeout, self.handle_status()).await?;
+
+                Ok(None)
+            }
             HandshakeNextState::Login => {
-                self.handle_login(protocol_version.0).await?;
-                self.handle_data_transfer().await?;
+                self.phase = Phase::Login;
+
+                // Before anything is decrypted, which is the expensive part.
+                if !self.server.login_limiter.check(ip) {
+                    self.io
+                        .send_packet(&CLoginDisconnect {
+                            reason: "Too many login attempts, try again later"
+                                .color(Color::WHITE)
+                                .into(),
+                        })
+                        .await?;
+
+                    return Err(ClientError::RateLimited);
+                }
+
+                let user = deadline(self.server.login_timeout, self.handle_login(ver)).await?;
+
+                Ok(Some(user))
             }
         }
+    }
 
-        Ok(())
+    /// Tells a client which is still logging in that the server is going
+    /// away, anything else is simply dropped.
+    async fn disconnect_for_shutdown(&mut self) {
+        if self.phase != Phase::Login {
+            return;
+        }
+
+        let disconnect = CLoginDisconnect {
+            reason: self
+                .server
+                .shutdown_message
+                .as_str()
+                .color(Color::WHITE)
+                .into(),
+        };
+
+        if let Err(e) = self.io.send_packet(&disconnect).await {
+            log::debug!("Failed to disconnect {}: {e}", self.remote_addr);
+        }
+
+        log::info!("Disconnected {} during login", self.remote_addr);
+    }
+
+    async fn relay(mut self, user: User) -> Result<(), ClientError> {
+        // Logged in clients have their own limits.
+        self.permit = None;
+
+        let (reader, writer) = self.io.into_split();
+        let session = Arc::new(Session {
+            user,
+            remote_addr: self.remote_addr,
+        });
+
+        self.server.handler.session_started(&session).await;
+
+        let res = Relay::new(writer, session.clone(), self.server.clone())
+            .run(reader)
+            .await
+            .map_err(ClientError::from);
+
+        self.server.handler.session_ended(&session, &res).await;
+        res
     }
 
-    async fn handle_status(mut self, ver: i32) -> Result<()> {
+    async fn handle_status(&mut self) -> Result<(), ClientError> {
+        let ping = self
+            .server
+            .handler
+            .status(self.remote_addr, &self.server.server_list_ping)
+            .await;
+
+        if let ServerListPing::Ignore = ping {
+            return Ok(());
+        }
+
         self.io.recv_packet::<SStatusRequest>().await?;
         self.io
             .send_packet(&CStatusResponse {
-                json: &serde_json::to_string(&self.server.server_list_ping)?,
+                json: &serde_json::to_string(&ping).map_err(anyhow::Error::from)?,
             })
             .await?;
 
@@ -97,7 +273,7 @@ impl Client {
         Ok(())
     }
 
-    async fn handle_login(&mut self, ver: i32) -> Result<()> {
+    async fn handle_login(&mut self, ver: i32) -> Result<User, ClientError> {
         // TODO: remove as i32
         if ver != CURRENT_MC_PROTOCOL as i32 {
             // TODO: normal errors
@@ -107,33 +283,48 @@ impl Client {
                 })
                 .await?;
 
-            bail!("Client use old version");
+            return Err(ClientError::Protocol(anyhow!(
+                "unsupported protocol version {ver}"
+            )));
         }
 
         let SHello { username, uuid } = self.io.recv_packet().await?;
-        let private_uuid = match self
+        let username = username.to_string();
+
+        let user = match self
             .server
-            .login_data
-            .get(username.0)
-            .copied()
-            .ok_or(anyhow!("Username not found"))
+            .handler
+            .authenticate(&username, uuid, self.remote_addr)
+            .await
         {
-            Ok((public_uuid, private_uuid)) if public_uuid == uuid => private_uuid,
-            _ => {
+            Some(user) => user,
+            None => {
                 self.io
                     .send_packet(&CLoginDisconnect {
                         reason: "ты не в вайтлисте ъ".color(Color::WHITE).into(),
                     })
                     .await?;
 
-                bail!("Client have incorrect name or uuid");
+                return Err(ClientError::Auth(format!(
+                    "unknown name or public UUID for `{username}`"
+                )));
             }
         };
 
-        let username = username.to_string();
-
         self.encrypt_connection().await?;
 
+        let threshold = self.server.compression_threshold;
+
+        if threshold.0 >= 0 {
+            self.io
+                .send_packet(&CLoginCompression {
+                    threshold: VarInt(threshold.0),
+                })
+                .await?;
+
+            self.io.set_compression(threshold);
+        }
+
         self.io
             .send_packet(&CLoginFinished {
                 uuid,
@@ -149,7 +340,7 @@ impl Client {
         // And if auth fails, then this is a serious warning sign
         // That the client's traffic is being listened to
         self.info = match self.io.recv_packet::<SClientInformation>().await {
-            Ok(info) if info.private_uuid == private_uuid => Some(info),
+            Ok(info) if info.private_uuid == user.private_uuid => Some(info),
             _ => {
                 self.io
                     .send_packet(&CLoginDisconnect {
@@ -163,16 +354,18 @@ impl Client {
                     username
                 );
 
-                bail!("Client failed second login by uuid");
+                return Err(ClientError::Auth(format!(
+                    "wrong private UUID for `{username}`"
+                )));
             }
         };
 
         log::info!("Accepted login from {}", self.remote_addr);
 
-        Ok(())
+        Ok(user)
     }
 
-    async fn encrypt_connection(&mut self) -> Result<()> {
+    async fn encrypt_connection(&mut self) -> Result<(), ClientError> {
         let server_verify_token: [u8; 16] = rand::random();
 
         self.io
@@ -190,22 +383,24 @@ impl Client {
             verify_token: encrypted_verify_token,
         } = self.io.recv_packet().await?;
 
-        let shared_secret = self
-            .server
-            .private_key
-            .decrypt(Pkcs1v15Encrypt, shared_secret)?;
+        let decrypt = |data| {
+            self.server
+                .private_key
+                .decrypt(Pkcs1v15Encrypt, data)
+                .map_err(|e| ClientError::Protocol(anyhow!("failed to decrypt: {e}")))
+        };
 
-        let verify_token = self
-            .server
-            .private_key
-            .decrypt(Pkcs1v15Encrypt, encrypted_verify_token)?;
+        let shared_secret = decrypt(shared_secret)?;
+        let verify_token = decrypt(encrypted_verify_token)?;
 
-        ensure!(
-            server_verify_token.as_slice() == verify_token,
-            "verify tokens do not match"
-        );
+        if server_verify_token.as_slice() != verify_token {
+            return Err(ClientError::Protocol(anyhow!("verify tokens do not match")));
+        }
 
-        let key: [u8; 16] = shared_secret.as_slice().try_into()?;
+        let key: [u8; 16] = shared_secret
+            .as_slice()
+            .try_into()
+            .map_err(|_| ClientError::Protocol(anyhow!("shared secret isn't 16 bytes")))?;
 
         self.io.enable_encryption(&key);
 
@@ -213,71 +408,15 @@ impl Client {
 
         Ok(())
     }
-
-    async fn handle_data_transfer(&mut self) -> Result<()> {
-        let udp = UdpSocket::bind("0.0.0.0:123").await?;
-
-        loop {
-            match self.io.recv_packet::<SData>().await?.data_type {
-                SDataTypeByte::Connect { ip, port, is_udp } => {
-                    let connection_id = self.next_connection_id;
-                    self.next_connection_id = self.next_connection_id.wrapping_add(1);
-
-                    if is_udp {
-                        self.connections
-                            .insert(connection_id, Connection::Udp((ip, port)));
-                    } else {
-                        self.connections.insert(
-                            connection_id,
-                            Connection::Tcp(TcpStream::connect((ip, port)).await?),
-                        );
-                    }
-
-                    self.io
-                        .send_packet(&CData {
-                            data_type: CDataTypeByte::Connect {
-                                ip,
-                                port,
-                                is_udp,
-                                connection_id,
-                            },
-                        })
-                        .await?;
-                }
-                SDataTypeByte::Process {
-                    connection_id,
-                    data,
-                } => {
-                    match self
-                        .connections
-                        .get_mut(&connection_id)
-                        .ok_or(anyhow!("Connection not found"))?
-                    {
-                        Connection::Tcp(stream) => {
-                            stream.write_all(data).await?;
-                        }
-                        Connection::Udp((ip, port)) => {
-                            udp.send_to(data, (*ip, *port)).await?;
-                        }
-                    }
-                }
-                SDataTypeByte::Shutdown { connection_id } => {
-                    if let Connection::Tcp(mut stream) = self
-                        .connections
-                        .remove(&connection_id)
-                        .ok_or(anyhow!("Connection not found"))?
-                    {
-                        stream.shutdown().await?;
-                    }
-                }
-            }
-        }
-
-        Ok(())
-    }
 }
 
-enum Connection {
-    Tcp(TcpStream),
-    Udp((IpAddr, u16)),
+/// Fails `future` with a timeout once it takes longer than `timeout`, so
+/// clients sending slowly can't hold on to their connection.
+async fn deadline<T>(
+    timeout: Duration,
+    future: impl Future<Output = Result<T, ClientError>>,
+) -> Result<T, ClientError> {
+    time::timeout(timeout, future)
+        .await
+        .unwrap_or_else(|_| Err(io::Error::from(ErrorKind::TimedOut).into()))
 }
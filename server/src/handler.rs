@@ -0,0 +1,135 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+};
+
+use uuid::Uuid;
+
+use crate::{config::User, connection::ClientError, ping::ServerListPing};
+
+/// Decides who may log in and what they may do, so the server can be embedded
+/// with its own policy.
+///
+/// Apart from [`authenticate`](Self::authenticate), every hook has a default:
+/// connects are allowed, binds are limited to the user's `bind_ports` and the
+/// status is the one the server was built with. The `server` binary uses
+/// [`Whitelist`].
+pub trait Handler: Send + Sync + 'static {
+    /// Looks up the user logging in as `name`, `None` refuses the login.
+    ///
+    /// The name and `public_uuid` are sent before the connection is encrypted.
+    /// Once it is, the client has to prove itself with the `private_uuid` of
+    /// the returned user.
+    fn authenticate(
+        &self,
+        name: &str,
+        public_uuid: Uuid,
+        remote_addr: SocketAddr,
+    ) -> impl Future<Output = Option<User>> + Send;
+
+    /// Whether the session may connect to `host`. Domains are checked before
+    /// they are resolved.
+    fn authorize_connect(
+        &self,
+        session: &Session,
+        host: &Host,
+        port: u16,
+        is_udp: bool,
+    ) -> impl Future<Output = bool> + Send {
+        let _ = (session, host, port, is_udp);
+        async { true }
+    }
+
+    /// Whether the session may have the server listen on `port`.
+    fn authorize_bind(&self, session: &Session, port: u16) -> impl Future<Output = bool> + Send {
+        let allowed = session
+            .user
+            .bind_ports
+            .iter()
+            .any(|range| range.contains(port));
+        async move { allowed }
+    }
+
+    /// Called once the user logged in, before any of its requests are handled.
+    fn session_started(&self, session: &Session) -> impl Future<Output = ()> + Send {
+        let _ = session;
+        async {}
+    }
+
+    /// Called when the session is over, with the error which ended it.
+    fn session_ended(
+        &self,
+        session: &Session,
+        result: &Result<(), ClientError>,
+    ) -> impl Future<Output = ()> + Send {
+        let _ = (session, result);
+        async {}
+    }
+
+    /// What the multiplayer server list shows to `remote_addr`.
+    fn status(
+        &self,
+        remote_addr: SocketAddr,
+        configured: &ServerListPing,
+    ) -> impl Future<Output = ServerListPing> + Send {
+        let _ = remote_addr;
+        let ping = configured.clone();
+        async move { ping }
+    }
+}
+
+/// A logged in client.
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub user: User,
+    pub remote_addr: SocketAddr,
+}
+
+/// What the client asked to connect to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Host {
+    Ip(IpAddr),
+    Domain(String),
+}
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(ip) => ip.fmt(f),
+            Self::Domain(domain) => domain.fmt(f),
+        }
+    }
+}
+
+/// Lets in a fixed set of users, like the ones from the config file.
+#[derive(Clone, Debug, Default)]
+pub struct Whitelist {
+    users: HashMap<String, User>,
+}
+
+impl Whitelist {
+    pub fn new(users: impl IntoIterator<Item = User>) -> Self {
+        Self {
+            users: users
+                .into_iter()
+                .map(|user| (user.name.clone(), user))
+                .collect(),
+        }
+    }
+}
+
+impl Handler for Whitelist {
+    async fn authenticate(
+        &self,
+        name: &str,
+        public_uuid: Uuid,
+        _remote_addr: SocketAddr,
+    ) -> Option<User> {
+        self.users
+            .get(name)
+            .filter(|user| user.public_uuid == public_uuid)
+            .cloned()
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use tokio::{net::TcpStream, task::JoinSet, time};
+
+/// How long an attempt may run alone before the next address is tried too.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connects to whichever of `addrs` answers first, as described in RFC 8305.
+///
+/// Address families are interleaved, starting with the one the resolver put
+/// first. A new attempt starts as soon as the previous one fails, or after
+/// [`CONNECTION_ATTEMPT_DELAY`] while it is still pending. The error of the
+/// last failed attempt is returned if none of them succeed.
+pub async fn connect(addrs: Vec<SocketAddr>) -> io::Result<(TcpStream, SocketAddr)> {
+    let mut pending = interleave_families(addrs);
+    // Attempts still running are aborted once this is dropped.
+    let mut attempts = JoinSet::new();
+    let mut last_err = io::Error::new(ErrorKind::NotFound, "no addresses to connect to");
+
+    loop {
+        if let Some(addr) = pending.pop_front() {
+            attempts.spawn(async move { TcpStream::connect(addr).await.map(|s| (s, addr)) });
+        }
+
+        if attempts.is_empty() {
+            return Err(last_err);
+        }
+
+        // Wait until an attempt finishes or it's time to start the next one.
+        tokio::select! {
+            Some(res) = attempts.join_next() => match res.map_err(io::Error::other)? {
+                Ok(connected) => return Ok(connected),
+                Err(e) => last_err = e,
+            },
+            _ = time::sleep(CONNECTION_ATTEMPT_DELAY), if !pending.is_empty() => {}
+            else => {}
+        }
+    }
+}
+
+fn interleave_families(addrs: Vec<SocketAddr>) -> VecDeque<SocketAddr> {
+    let Some(prefer_ipv6) = addrs.first().map(SocketAddr::is_ipv6) else {
+        return VecDeque::new();
+    };
+
+    let (mut preferred, mut other): (VecDeque<_>, VecDeque<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == prefer_ipv6);
+
+    let mut res = VecDeque::with_capacity(preferred.len() + other.len());
+
+    while !preferred.is_empty() || !other.is_empty() {
+        res.extend(preferred.pop_front());
+        res.extend(other.pop_front());
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn empty() {
+        assert!(interleave_families(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn single_family_keeps_order() {
+        let v4 = addrs(&["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"]);
+        assert_eq!(Vec::from(interleave_families(v4.clone())), v4);
+
+        let v6 = addrs(&["[2001:db8::1]:80", "[2001:db8::2]:80"]);
+        assert_eq!(Vec::from(interleave_families(v6.clone())), v6);
+    }
+
+    #[test]
+    fn starts_with_first_family() {
+        let interleaved = interleave_families(addrs(&[
+            "[2001:db8::1]:80",
+            "[2001:db8::2]:80",
+            "10.0.0.1:80",
+            "10.0.0.2:80",
+        ]));
+        let expected = addrs(&[
+            "[2001:db8::1]:80",
+            "10.0.0.1:80",
+            "[2001:db8::2]:80",
+            "10.0.0.2:80",
+        ]);
+        assert_eq!(Vec::from(interleaved), expected);
+
+        let interleaved =
+            interleave_families(addrs(&["10.0.0.1:80", "[2001:db8::1]:80", "10.0.0.2:80"]));
+        let expected = addrs(&["10.0.0.1:80", "[2001:db8::1]:80", "10.0.0.2:80"]);
+        assert_eq!(Vec::from(interleaved), expected);
+    }
+
+    #[test]
+    fn leftovers_keep_order() {
+        let interleaved = interleave_families(addrs(&[
+            "10.0.0.1:80",
+            "[2001:db8::1]:80",
+            "[2001:db8::2]:80",
+            "[2001:db8::3]:80",
+        ]));
+        let expected = addrs(&[
+            "10.0.0.1:80",
+            "[2001:db8::1]:80",
+            "[2001:db8::2]:80",
+            "[2001:db8::3]:80",
+        ]);
+        assert_eq!(Vec::from(interleaved), expected);
+
+        let interleaved = interleave_families(addrs(&[
+            "[2001:db8::1]:80",
+            "10.0.0.1:80",
+            "10.0.0.2:80",
+            "10.0.0.3:80",
+        ]));
+        let expected = addrs(&[
+            "[2001:db8::1]:80",
+            "10.0.0.1:80",
+            "10.0.0.2:80",
+            "10.0.0.3:80",
+        ]);
+        assert_eq!(Vec::from(interleaved), expected);
+    }
+}
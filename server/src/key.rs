@@ -0,0 +1,73 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use rsa::{
+    RsaPrivateKey,
+    pkcs8::{DecodePrivateKey, EncodePrivateKey, EncodePublicKey, LineEnding},
+    rand_core::OsRng,
+    traits::PublicKeyParts,
+};
+
+/// Loads the server's private key from a PKCS#8 PEM file, or generates a new
+/// `bits` sized key into it if the file doesn't exist yet.
+pub fn load_or_generate(path: &Path, bits: usize) -> Result<RsaPrivateKey> {
+    match fs::read_to_string(path) {
+        Ok(pem) => {
+            let key = RsaPrivateKey::from_pkcs8_pem(&pem)
+                .with_context(|| format!("failed to parse key {}", path.display()))?;
+
+            if key.size() * 8 != bits {
+                log::warn!(
+                    "Key {} has {} bits instead of the configured {bits}, delete it to generate \
+                     a new one",
+                    path.display(),
+                    key.size() * 8
+                );
+            }
+
+            Ok(key)
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            log::info!("Generating {bits}-bit RSA key into {}", path.display());
+
+            let key = RsaPrivateKey::new(&mut OsRng, bits)?;
+            write_pem(path, &key)
+                .with_context(|| format!("failed to write key {}", path.display()))?;
+
+            Ok(key)
+        }
+        Err(e) => Err(e).with_context(|| format!("failed to read key {}", path.display())),
+    }
+}
+
+/// DER encoding of the public key, as sent to clients.
+pub fn public_key_der(key: &RsaPrivateKey) -> Result<Box<[u8]>> {
+    Ok(key
+        .to_public_key()
+        .to_public_key_der()?
+        .into_vec()
+        .into_boxed_slice())
+}
+
+fn write_pem(path: &Path, key: &RsaPrivateKey) -> Result<()> {
+    let pem = key.to_pkcs8_pem(LineEnding::LF)?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+
+    // Nobody but the server has any business reading the key.
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options.open(path)?.write_all(pem.as_bytes())?;
+
+    Ok(())
+}
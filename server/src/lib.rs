@@ -0,0 +1,28 @@
+//! The rkp server. The `server` binary runs it from a config file, embedding
+//! it with a [`Handler`] puts logins and connects under your own policy:
+//!
+//! ```no_run
+//! # async fn example(private_key: rsa::RsaPrivateKey, users: Vec<server::config::User>)
+//! # -> anyhow::Result<()> {
+//! use std::sync::Arc;
+//!
+//! let server = server::Server::builder(private_key).users(users).build()?;
+//! Arc::new(server).start(&["0.0.0.0:25565".parse()?]).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub use crate::{
+    handler::{Handler, Host, Session, Whitelist},
+    server::{Builder, Server},
+};
+
+pub mod config;
+pub mod connection;
+pub mod handler;
+pub mod happy_eyeballs;
+pub mod key;
+pub mod limits;
+pub mod ping;
+pub mod relay;
+pub mod server;
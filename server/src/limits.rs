@@ -0,0 +1,237 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Mutex,
+    time::Instant,
+};
+
+use crate::config::RateLimit;
+
+/// How many networks are tracked before full buckets are forgotten.
+const MIN_PRUNE_LEN: usize = 1024;
+
+/// Token buckets per source network, so one network can't keep the server
+/// busy with expensive requests.
+pub struct RateLimiter {
+    limit: RateLimit,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    state: Mutex<State>,
+}
+
+struct State {
+    buckets: HashMap<IpAddr, Bucket>,
+    /// Full buckets are forgotten once this many networks are tracked, so
+    /// the map doesn't grow with every address ever seen.
+    prune_len: usize,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit, ipv4_prefix: u8, ipv6_prefix: u8) -> Self {
+        Self {
+            limit,
+            ipv4_prefix,
+            ipv6_prefix,
+            state: Mutex::new(State {
+                buckets: HashMap::new(),
+                prune_len: MIN_PRUNE_LEN,
+            }),
+        }
+    }
+
+    /// Takes a token from the bucket of the network `ip` belongs to, `false`
+    /// if it is empty.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> bool {
+        if self.limit.per_minute == 0 {
+            return true;
+        }
+
+        let network = self.network(ip);
+
+        let mut state = self.state.lock().unwrap();
+
+        if state.buckets.len() >= state.prune_len {
+            state
+                .buckets
+                .retain(|_, bucket| self.refill(bucket, now) < f64::from(self.limit.burst));
+            state.prune_len = (state.buckets.len() * 2).max(MIN_PRUNE_LEN);
+        }
+
+        let bucket = state.buckets.entry(network).or_insert(Bucket {
+            tokens: f64::from(self.limit.burst),
+            updated: now,
+        });
+
+        let tokens = self.refill(bucket, now);
+
+        if tokens < 1.0 {
+            return false;
+        }
+
+        bucket.tokens = tokens - 1.0;
+        bucket.updated = now;
+
+        true
+    }
+
+    /// How many tokens the bucket has at `now`.
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        let refilled = bucket.tokens + elapsed * f64::from(self.limit.per_minute) / 60.0;
+
+        refilled.min(f64::from(self.limit.burst))
+    }
+
+    /// Clears the host bits of `ip`.
+    fn network(&self, ip: IpAddr) -> IpAddr {
+        // Dual stack sockets see IPv4 clients as mapped IPv6 addresses.
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.ipv4_prefix));
+                IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask.unwrap_or(0)))
+            }
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.ipv6_prefix));
+                IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask.unwrap_or(0)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn limiter(burst: u32, per_minute: u32) -> RateLimiter {
+        RateLimiter::new(RateLimit { burst, per_minute }, 32, 64)
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn burst_then_refill() {
+        let limiter = limiter(3, 60);
+        let client = ip("192.0.2.1");
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.check_at(client, start));
+        }
+        assert!(!limiter.check_at(client, start));
+        assert!(!limiter.check_at(client, start + Duration::from_millis(900)));
+
+        // One token per second.
+        assert!(limiter.check_at(client, start + Duration::from_secs(1)));
+        assert!(!limiter.check_at(client, start + Duration::from_secs(1)));
+
+        // Never more than the burst, however long the network was quiet.
+        let later = start + Duration::from_secs(3600);
+        for _ in 0..3 {
+            assert!(limiter.check_at(client, later));
+        }
+        assert!(!limiter.check_at(client, later));
+    }
+
+    #[test]
+    fn networks_have_own_buckets() {
+        let limiter = limiter(1, 60);
+        let now = Instant::now();
+
+        assert!(limiter.check_at(ip("192.0.2.1"), now));
+        assert!(!limiter.check_at(ip("192.0.2.1"), now));
+        assert!(limiter.check_at(ip("192.0.2.2"), now));
+        assert!(limiter.check_at(ip("2001:db8::1"), now));
+        // Same /64.
+        assert!(!limiter.check_at(ip("2001:db8::ffff"), now));
+        assert!(limiter.check_at(ip("2001:db8:0:1::1"), now));
+    }
+
+    #[test]
+    fn unlimited() {
+        let limiter = limiter(1, 0);
+        let now = Instant::now();
+
+        for _ in 0..10 {
+            assert!(limiter.check_at(ip("192.0.2.1"), now));
+        }
+    }
+
+    #[test]
+    fn network_prefixes() {
+        let limit = RateLimit {
+            burst: 1,
+            per_minute: 1,
+        };
+
+        let limiter = RateLimiter::new(limit, 0, 0);
+        assert_eq!(limiter.network(ip("192.0.2.1")), ip("0.0.0.0"));
+        assert_eq!(limiter.network(ip("2001:db8::1")), ip("::"));
+
+        let limiter = RateLimiter::new(limit, 32, 128);
+        assert_eq!(limiter.network(ip("192.0.2.1")), ip("192.0.2.1"));
+        assert_eq!(limiter.network(ip("2001:db8::1")), ip("2001:db8::1"));
+
+        let limiter = RateLimiter::new(limit, 24, 64);
+        assert_eq!(limiter.network(ip("192.0.2.1")), ip("192.0.2.0"));
+        assert_eq!(
+            limiter.network(ip("2001:db8:1:2:3:4:5:6")),
+            ip("2001:db8:1:2::")
+        );
+    }
+
+    #[test]
+    fn mapped_ipv4_uses_ipv4_prefix() {
+        let limiter = RateLimiter::new(
+            RateLimit {
+                burst: 1,
+                per_minute: 1,
+            },
+            24,
+            128,
+        );
+
+        assert_eq!(limiter.network(ip("::ffff:192.0.2.1")), ip("192.0.2.0"));
+
+        let now = Instant::now();
+        assert!(limiter.check_at(ip("192.0.2.1"), now));
+        assert!(!limiter.check_at(ip("::ffff:192.0.2.2"), now));
+    }
+
+    #[test]
+    fn prune_forgets_full_buckets() {
+        let limiter = limiter(2, 60);
+        let start = Instant::now();
+
+        // Every network takes one token, the first one both of its tokens.
+        let networks: Vec<_> = (0..MIN_PRUNE_LEN as u32)
+            .map(|i| IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + i)))
+            .collect();
+        assert!(limiter.check_at(networks[0], start));
+        for &network in &networks {
+            assert!(limiter.check_at(network, start));
+        }
+        assert_eq!(limiter.state.lock().unwrap().buckets.len(), MIN_PRUNE_LEN);
+
+        // A second later every bucket but the first is full again and dropped.
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.check_at(ip("192.0.2.1"), later));
+
+        let state = limiter.state.lock().unwrap();
+        assert_eq!(state.buckets.len(), 2);
+        assert!(state.buckets.contains_key(&networks[0]));
+        assert_eq!(state.prune_len, MIN_PRUNE_LEN);
+    }
+}
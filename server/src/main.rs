@@ -1,16 +1,70 @@
-use crate::server::Server;
 use anyhow::Result;
-use std::sync::Arc;
+use protocol::key_fingerprint;
+use server::{Server, config::Config, key};
+use std::{env, io, process, sync::Arc};
+use tokio::signal;
 
-pub mod connection;
-pub mod ping;
-pub mod server;
+const DEFAULT_CONFIG_PATH: &str = "rkp-server.toml";
 
 #[tokio::main]
 async fn main() -> Result<()> {
     simple_logger::init()?;
 
-    Arc::new(Server::new()?).start("0.0.0.0:25565").await?;
+    // `server [config]` runs the server, `server fingerprint [config]` only
+    // prints the fingerprint of its key.
+    let mut args = env::args().skip(1).peekable();
+    let print_fingerprint = args.next_if_eq("fingerprint").is_some();
+
+    let config_path = args.next();
+    let config = Config::load(config_path.as_deref().unwrap_or(DEFAULT_CONFIG_PATH))?;
+
+    if print_fingerprint {
+        let private_key = key::load_or_generate(&config.key_path, config.key_size)?;
+        println!("{}", key_fingerprint(&key::public_key_der(&private_key)?));
+
+        return Ok(());
+    }
+
+    let server = Arc::new(Server::new(&config)?);
+
+    tokio::spawn({
+        let server = server.clone();
+
+        async move {
+            if let Err(e) = shutdown_signal().await {
+                log::error!("Failed to listen for shutdown signals: {e}");
+                return;
+            }
+
+            log::info!("Shutting down, send the signal again to exit right away");
+            server.shutdown();
+
+            if shutdown_signal().await.is_ok() {
+                log::warn!("Exiting without waiting for clients");
+                process::exit(1);
+            }
+        }
+    });
+
+    server.start(&config.bind).await?;
+
+    log::info!("Server stopped");
 
     Ok(())
 }
+
+/// Waits for Ctrl+C, or SIGTERM on Unix.
+async fn shutdown_signal() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+
+        tokio::select! {
+            res = signal::ctrl_c() => res,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    signal::ctrl_c().await
+}
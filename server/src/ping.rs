@@ -1,8 +1,14 @@
+use std::fs;
+
+use anyhow::{Context, Result};
+use base64::{Engine, engine::general_purpose::STANDARD};
 use protocol::packet_id::CURRENT_MC_PROTOCOL;
 use serde::Serialize;
 use uuid::Uuid;
 use valence_text::{Color, IntoText, Text};
 
+use crate::config::Status;
+
 #[derive(Clone, Debug, Serialize)]
 #[serde(untagged)]
 pub enum ServerListPing {
@@ -16,6 +22,38 @@ pub enum ServerListPing {
     Ignore,
 }
 
+impl ServerListPing {
+    pub fn new(status: &Status) -> Result<Self> {
+        if !status.enabled {
+            return Ok(Self::Ignore);
+        }
+
+        let favicon = match &status.favicon {
+            Some(path) => {
+                let png = fs::read(path)
+                    .with_context(|| format!("failed to read favicon {}", path.display()))?;
+
+                Some(format!("data:image/png;base64,{}", STANDARD.encode(png)))
+            }
+            None => None,
+        };
+
+        Ok(Self::Respond {
+            version: Version {
+                name: status.version_name.clone(),
+                protocol: CURRENT_MC_PROTOCOL as i32,
+            },
+            players: Players {
+                online: status.online_players,
+                max: status.max_players,
+                sample: Vec::new(),
+            },
+            desc: status.description.clone().color(Color::GRAY),
+            favicon,
+        })
+    }
+}
+
 impl Default for ServerListPing {
     fn default() -> Self {
         Self::Respond {
@@ -29,9 +67,9 @@ impl Default for ServerListPing {
 
 #[derive(Clone, Debug, Serialize)]
 pub struct Players {
-    online: i32,
-    max: i32,
-    sample: Vec<PlayerSampleEntry>,
+    pub online: i32,
+    pub max: i32,
+    pub sample: Vec<PlayerSampleEntry>,
 }
 
 impl Default for Players {
@@ -52,8 +90,8 @@ pub struct PlayerSampleEntry {
 
 #[derive(Clone, Debug, Serialize)]
 pub struct Version {
-    name: String,
-    protocol: i32,
+    pub name: String,
+    pub protocol: i32,
 }
 
 impl Default for Version {
@@ -0,0 +1,909 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use anyhow::{Result, bail};
+use protocol::{
+    clientbound::{
+        login::login_disconnect::CLoginDisconnect,
+        transfer::data::{BindFailReason, CData, CDataTypeByte, ConnectFailReason, ResetReason},
+    },
+    packet_io::{PacketReadHalf, PacketWriteHalf},
+    serverbound::transfer::data::{SData, SDataTypeByte},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        self, TcpListener, TcpStream, UdpSocket,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    sync::{Mutex, mpsc, watch},
+    task::{AbortHandle, JoinSet},
+    time::{self, Instant},
+};
+use tokio_util::sync::{CancellationToken, DropGuard};
+use valence_text::{Color, IntoText};
+
+use crate::{
+    handler::{Handler, Host, Session},
+    happy_eyeballs,
+    server::Server,
+};
+
+/// How much data is read from a remote socket before it is sent to the client.
+const RELAY_BUF_SIZE: usize = 16384;
+/// Large enough for any UDP datagram, so none of them get truncated.
+const UDP_BUF_SIZE: usize = 65535;
+/// How long a listener waits after a failed accept before trying again.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+/// How many chunks from the client may wait for a slow remote host before its
+/// connection is reset.
+const WRITE_QUEUE_SIZE: usize = 256;
+
+/// Forwards data between a logged in client and the remote hosts it asked for.
+///
+/// Packets from the client are handled on the session task, while every remote
+/// connection gets its own reader task sending data back through the shared
+/// writer half, and TCP connections a writer task, so one slow remote host
+/// can't hold up the others.
+pub struct Relay<H> {
+    handle: RelayHandle,
+    session: Arc<Session>,
+    server: Arc<Server<H>>,
+
+    next_connection_id: u16,
+    connections: HashMap<u16, Connection>,
+    next_listener_id: u16,
+    listeners: HashMap<u16, Listener>,
+    event_rx: mpsc::UnboundedReceiver<Event>,
+    // Aborts all remaining tasks when the session ends.
+    tasks: JoinSet<()>,
+    /// Set once the server shuts down, the session then ends as soon as its
+    /// connections are closed.
+    draining: bool,
+}
+
+/// The parts of a relay which are shared with the tasks it spawns.
+#[derive(Clone)]
+struct RelayHandle {
+    writer: Arc<Mutex<PacketWriteHalf>>,
+    event_tx: mpsc::UnboundedSender<Event>,
+    remote_addr: SocketAddr,
+}
+
+impl RelayHandle {
+    async fn send(&self, data_type: CDataTypeByte<'_>) -> Result<()> {
+        self.writer
+            .lock()
+            .await
+            .send_packet(&CData { data_type })
+            .await
+    }
+
+    /// Sends for a reader, unless its connection was closed in the meantime.
+    /// Readers stop this way instead of being aborted, as an abort in the
+    /// middle of a packet would corrupt the stream to the client, and a
+    /// packet sent after the close could end up on a connection which
+    /// reuses the id.
+    async fn send_while_open(
+        &self,
+        data_type: CDataTypeByte<'_>,
+        closed: &CancellationToken,
+    ) -> Result<bool> {
+        let mut writer = self.writer.lock().await;
+
+        if closed.is_cancelled() {
+            return Ok(false);
+        }
+
+        writer.send_packet(&CData { data_type }).await?;
+
+        Ok(true)
+    }
+
+    fn notify(&self, event: Event) {
+        // The session task is gone only if the whole relay is shutting down.
+        let _ = self.event_tx.send(event);
+    }
+}
+
+enum Connection {
+    Tcp {
+        /// Feeds the writer task, `None` once the client shut down its
+        /// direction. The writer then shuts down the remote side after
+        /// writing what is left.
+        writes: Option<mpsc::Sender<Vec<u8>>>,
+        writer: AbortHandle,
+        /// Stops the reader task once the connection is dropped.
+        _reader: DropGuard,
+        /// Set while the client asks the reader to wait.
+        paused: watch::Sender<bool>,
+        read_closed: bool,
+    },
+    Udp {
+        association: Arc<UdpAssociation>,
+        _reader: DropGuard,
+    },
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // A writer which is still being fed is cut off, one which only has
+        // the rest to write finishes by itself.
+        if let Self::Tcp {
+            writes: Some(_),
+            writer,
+            ..
+        } = self
+        {
+            writer.abort();
+        }
+    }
+}
+
+/// Accepts connections on a port the client asked for, until it is dropped.
+struct Listener(AbortHandle);
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+struct UdpAssociation {
+    socket: UdpSocket,
+    last_active: StdMutex<Instant>,
+}
+
+impl UdpAssociation {
+    fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+
+    fn idle_deadline(&self, timeout: Duration) -> Instant {
+        *self.last_active.lock().unwrap() + timeout
+    }
+}
+
+/// Sent to the session task by the tasks it spawned.
+enum Event {
+    /// A connect requested by the client succeeded.
+    Connected {
+        request_id: u16,
+        addr: SocketAddr,
+        remote: Remote,
+    },
+    /// A listener of the client accepted a connection.
+    Inbound {
+        listener_id: u16,
+        stream: TcpStream,
+        addr: SocketAddr,
+    },
+    /// The remote host finished sending, but may still receive data.
+    Eof(u16),
+    /// Writing to the remote host failed, the client wasn't told yet.
+    WriteFailed(u16, ErrorKind),
+    /// The connection can't be used anymore, the client was already told so.
+    Reset(u16),
+}
+
+enum Remote {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+}
+
+impl<H: Handler> Relay<H> {
+    pub fn new(writer: PacketWriteHalf, session: Arc<Session>, server: Arc<Server<H>>) -> Self {
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+
+        Self {
+            handle: RelayHandle {
+                writer: Arc::new(Mutex::new(writer)),
+                event_tx,
+                remote_addr: session.remote_addr,
+            },
+            session,
+            server,
+
+            next_connection_id: 0,
+            connections: HashMap::new(),
+            next_listener_id: 0,
+            listeners: HashMap::new(),
+            event_rx,
+            tasks: JoinSet::new(),
+            draining: false,
+        }
+    }
+
+    /// Relays until the client leaves, or until the server shut down and the
+    /// session is drained.
+    pub async fn run(mut self, mut reader: PacketReadHalf) -> Result<()> {
+        let server = self.server.clone();
+        let remote_addr = self.handle.remote_addr;
+
+        loop {
+            if self.draining && self.connections.is_empty() {
+                log::info!("Session of {remote_addr} drained");
+                break;
+            }
+
+            tokio::select! {
+                packet = reader.recv_packet::<SData>() => match packet {
+                    Ok(packet) => self.handle_packet(packet.data_type).await?,
+                    // The client logged out, there is nobody left to tell.
+                    Err(e) if is_eof(&e) => return Ok(()),
+                    Err(e) => return Err(e),
+                },
+                Some(event) = self.event_rx.recv() => self.handle_event(event).await?,
+                // Finished tasks stay in the set until they are joined.
+                Some(_) = self.tasks.join_next(), if !self.tasks.is_empty() => {}
+                _ = server.shutting_down.cancelled(), if !self.draining => {
+                    // Listeners would only bring in new connections.
+                    self.listeners.clear();
+                    self.draining = true;
+                }
+                _ = server.drain_expired.cancelled() => {
+                    let connection_ids: Vec<_> = self.connections.keys().copied().collect();
+                    self.connections.clear();
+
+                    for connection_id in connection_ids {
+                        self.handle
+                            .send(CDataTypeByte::Reset {
+                                connection_id,
+                                reason: ResetReason::Shutdown,
+                            })
+                            .await?;
+                    }
+
+                    break;
+                }
+            }
+        }
+
+        let disconnect = CLoginDisconnect {
+            reason: server.shutdown_message.as_str().color(Color::WHITE).into(),
+        };
+
+        self.handle
+            .writer
+            .lock()
+            .await
+            .send_packet(&disconnect)
+            .await
+    }
+
+    async fn handle_packet(&mut self, data_type: SDataTypeByte<'_>) -> Result<()> {
+        match data_type {
+            SDataTypeByte::Connect {
+                request_id,
+                ip,
+                port,
+                is_udp,
+            } => self.dial(request_id, Host::Ip(ip), port, is_udp).await?,
+            SDataTypeByte::ConnectDomain {
+                request_id,
+                domain,
+                port,
+                is_udp,
+            } => {
+                self.dial(request_id, Host::Domain(domain.to_string()), port, is_udp)
+                    .await?
+            }
+            SDataTypeByte::Process {
+                connection_id,
+                data,
+            } => {
+                let res = match self.connections.get_mut(&connection_id) {
+                    Some(Connection::Tcp {
+                        writes: Some(writes),
+                        ..
+                    }) => writes.try_send(data.to_vec()).map_err(|_| {
+                        log::debug!(
+                            "Connection {connection_id} of {} can't keep up, resetting it",
+                            self.handle.remote_addr
+                        );
+                        ResetReason::Congested
+                    }),
+                    Some(Connection::Tcp { writes: None, .. }) => {
+                        bail!("Data sent after shutdown of connection {connection_id}")
+                    }
+                    Some(Connection::Udp { association, .. }) => {
+                        association.touch();
+                        association.socket.send(data).await.map(drop).map_err(|e| {
+                            log::debug!(
+                                "Connection {connection_id} of {} failed: {e}",
+                                self.handle.remote_addr
+                            );
+                            e.kind().into()
+                        })
+                    }
+                    // The connection could have been closed by the remote host
+                    // while this data was on its way.
+                    None => Ok(()),
+                };
+
+                if let Err(reason) = res {
+                    self.reset(connection_id, reason).await?;
+                }
+            }
+            SDataTypeByte::Shutdown { connection_id } => {
+                match self.connections.get_mut(&connection_id) {
+                    Some(Connection::Tcp {
+                        writes,
+                        read_closed,
+                        ..
+                    }) => {
+                        // The writer shuts down the remote side once the
+                        // queue is empty.
+                        writes.take();
+
+                        if *read_closed {
+                            self.connections.remove(&connection_id);
+                        }
+                    }
+                    // UDP has nothing to half close.
+                    Some(Connection::Udp { .. }) => {
+                        self.connections.remove(&connection_id);
+                    }
+                    None => {}
+                }
+            }
+            SDataTypeByte::Reset { connection_id } => {
+                self.connections.remove(&connection_id);
+            }
+            SDataTypeByte::Bind { request_id, port } => self.bind(request_id, port).await?,
+            SDataTypeByte::Unbind { listener_id } => {
+                self.listeners.remove(&listener_id);
+            }
+            SDataTypeByte::Pause { connection_id } | SDataTypeByte::Resume { connection_id } => {
+                let pause = matches!(data_type, SDataTypeByte::Pause { .. });
+
+                // Datagrams the client can't keep up with are dropped there.
+                if let Some(Connection::Tcp { paused, .. }) = self.connections.get(&connection_id) {
+                    paused.send_replace(pause);
+                }
+            }
+            SDataTypeByte::Ping { request_id } => {
+                self.handle.send(CDataTypeByte::Pong { request_id }).await?
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_event(&mut self, event: Event) -> Result<()> {
+        match event {
+            Event::Connected {
+                request_id,
+                addr,
+                remote,
+            } => {
+                let connection_id = self.allocate_connection_id();
+
+                self.handle
+                    .send(CDataTypeByte::Connect {
+                        request_id,
+                        ip: addr.ip(),
+                        port: addr.port(),
+                        is_udp: matches!(remote, Remote::Udp(_)),
+                        connection_id,
+                    })
+                    .await?;
+
+                self.open_connection(connection_id, remote);
+            }
+            Event::Inbound {
+                listener_id,
+                stream,
+                addr,
+            } => {
+                // The listener could have been closed while the connection
+                // was on its way.
+                if !self.listeners.contains_key(&listener_id) {
+                    return Ok(());
+                }
+
+                let connection_id = self.allocate_connection_id();
+
+                self.handle
+                    .send(CDataTypeByte::Inbound {
+                        listener_id,
+                        connection_id,
+                        ip: addr.ip(),
+                        port: addr.port(),
+                    })
+                    .await?;
+
+                self.open_connection(connection_id, Remote::Tcp(stream));
+            }
+            Event::Eof(connection_id) => {
+                if let Some(Connection::Tcp {
+                    writes,
+                    read_closed,
+                    ..
+                }) = self.connections.get_mut(&connection_id)
+                {
+                    *read_closed = true;
+
+                    if writes.is_none() {
+                        self.connections.remove(&connection_id);
+                    }
+                }
+            }
+            Event::WriteFailed(connection_id, kind) => {
+                self.reset(connection_id, kind.into()).await?;
+            }
+            Event::Reset(connection_id) => {
+                self.connections.remove(&connection_id);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Closes a connection which failed on the server side and tells the
+    /// client, unless it was already closed.
+    async fn reset(&mut self, connection_id: u16, reason: ResetReason) -> Result<()> {
+        if self.connections.remove(&connection_id).is_none() {
+            return Ok(());
+        }
+
+        self.handle
+            .send(CDataTypeByte::Reset {
+                connection_id,
+                reason,
+            })
+            .await
+    }
+
+    async fn dial(&mut self, request_id: u16, host: Host, port: u16, is_udp: bool) -> Result<()> {
+        if self.draining {
+            let failed = CDataTypeByte::ConnectFailed {
+                request_id,
+                reason: ConnectFailReason::ShuttingDown,
+            };
+
+            return self.handle.send(failed).await;
+        }
+
+        // Connecting can take a while, so other connections are served in the
+        // meantime.
+        self.tasks.spawn(dial(
+            request_id,
+            host,
+            port,
+            is_udp,
+            self.server.clone(),
+            self.session.clone(),
+            self.handle.clone(),
+        ));
+
+        Ok(())
+    }
+
+    /// Listens on `port` for the client, if it is allowed to.
+    async fn bind(&mut self, request_id: u16, port: u16) -> Result<()> {
+        let remote_addr = self.handle.remote_addr;
+
+        let res = if self.draining {
+            log::debug!("{remote_addr} can't listen on port {port} while shutting down");
+            Err(BindFailReason::Other)
+        } else if self
+            .server
+            .handler
+            .authorize_bind(&self.session, port)
+            .await
+        {
+            TcpListener::bind((self.server.reverse_listen_ip, port))
+                .await
+                .map_err(|e| {
+                    log::debug!("{remote_addr} failed to listen on port {port}: {e}");
+                    e.kind().into()
+                })
+        } else {
+            log::debug!("{remote_addr} isn't allowed to listen on port {port}");
+            Err(BindFailReason::Forbidden)
+        };
+
+        let data_type = match res {
+            Ok(listener) => {
+                let port = listener.local_addr()?.port();
+                let listener_id = self.allocate_listener_id();
+
+                let accept = self
+                    .tasks
+                    .spawn(accept(listener, listener_id, self.handle.clone()));
+
+                self.listeners.insert(listener_id, Listener(accept));
+
+                log::info!("{remote_addr} is listening on port {port}");
+
+                CDataTypeByte::Bound {
+                    request_id,
+                    listener_id,
+                    port,
+                }
+            }
+            Err(reason) => CDataTypeByte::BindFailed { request_id, reason },
+        };
+
+        self.handle.send(data_type).await
+    }
+
+    /// Starts relaying a connection which the client was already told about.
+    fn open_connection(&mut self, connection_id: u16, remote: Remote) {
+        let connection = match remote {
+            Remote::Tcp(stream) => {
+                let (read, write) = stream.into_split();
+                let (writes, writes_rx) = mpsc::channel(WRITE_QUEUE_SIZE);
+
+                let closed = CancellationToken::new();
+                let (paused, paused_rx) = watch::channel(false);
+
+                self.tasks.spawn(relay_tcp(
+                    read,
+                    connection_id,
+                    self.handle.clone(),
+                    closed.clone(),
+                    paused_rx,
+                ));
+                let writer = self.tasks.spawn(write_tcp(
+                    write,
+                    writes_rx,
+                    connection_id,
+                    self.handle.clone(),
+                ));
+
+                Connection::Tcp {
+                    writes: Some(writes),
+                    writer,
+                    _reader: closed.drop_guard(),
+                    paused,
+                    read_closed: false,
+                }
+            }
+            Remote::Udp(socket) => {
+                let association = Arc::new(UdpAssociation {
+                    socket,
+                    last_active: StdMutex::new(Instant::now()),
+                });
+
+                let closed = CancellationToken::new();
+
+                self.tasks.spawn(relay_udp(
+                    association.clone(),
+                    connection_id,
+                    self.server.udp_idle_timeout,
+                    self.handle.clone(),
+                    closed.clone(),
+                ));
+
+                Connection::Udp {
+                    association,
+                    _reader: closed.drop_guard(),
+                }
+            }
+        };
+
+        self.connections.insert(connection_id, connection);
+    }
+
+    /// Picks the next connection id which isn't used by an open connection.
+    fn allocate_connection_id(&mut self) -> u16 {
+        while self.connections.contains_key(&self.next_connection_id) {
+            self.next_connection_id = self.next_connection_id.wrapping_add(1);
+        }
+
+        let connection_id = self.next_connection_id;
+        self.next_connection_id = self.next_connection_id.wrapping_add(1);
+        connection_id
+    }
+
+    fn allocate_listener_id(&mut self) -> u16 {
+        while self.listeners.contains_key(&self.next_listener_id) {
+            self.next_listener_id = self.next_listener_id.wrapping_add(1);
+        }
+
+        let listener_id = self.next_listener_id;
+        self.next_listener_id = self.next_listener_id.wrapping_add(1);
+        listener_id
+    }
+}
+
+/// Hands every connection accepted on a listener of the client to the
+/// session task.
+async fn accept(listener: TcpListener, listener_id: u16, handle: RelayHandle) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => handle.notify(Event::Inbound {
+                listener_id,
+                stream,
+                addr,
+            }),
+            Err(e) => {
+                // Usually running out of file descriptors, which may pass.
+                log::debug!(
+                    "Listener {listener_id} of {} failed: {e}",
+                    handle.remote_addr
+                );
+                time::sleep(ACCEPT_ERROR_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Connects to the remote host if the handler allows it, handing the
+/// connection to the session task or telling the client why it failed.
+async fn dial<H: Handler>(
+    request_id: u16,
+    host: Host,
+    port: u16,
+    is_udp: bool,
+    server: Arc<Server<H>>,
+    session: Arc<Session>,
+    handle: RelayHandle,
+) {
+    let remote_addr = handle.remote_addr;
+
+    if !server
+        .handler
+        .authorize_connect(&session, &host, port, is_udp)
+        .await
+    {
+        log::debug!("{remote_addr} isn't allowed to connect to {host}:{port}");
+
+        let failed = CDataTypeByte::ConnectFailed {
+            request_id,
+            reason: ConnectFailReason::Forbidden,
+        };
+
+        if let Err(e) = handle.send(failed).await {
+            log::debug!("Failed to relay data to {remote_addr}: {e}");
+        }
+
+        return;
+    }
+
+    let connect = async {
+        let addrs: Vec<_> = match &host {
+            Host::Ip(ip) => vec![SocketAddr::new(*ip, port)],
+            Host::Domain(domain) => match net::lookup_host((domain.as_str(), port)).await {
+                Ok(addrs) => addrs.collect(),
+                Err(e) => return Err((ConnectFailReason::Unresolved, e)),
+            },
+        };
+
+        if addrs.is_empty() {
+            let e = io::Error::new(ErrorKind::NotFound, "no addresses found");
+            return Err((ConnectFailReason::Unresolved, e));
+        }
+
+        if is_udp {
+            // Datagrams have no handshake to race, so the first address wins.
+            let addr = addrs[0];
+
+            match connect_udp(addr).await {
+                Ok(socket) => Ok((Remote::Udp(socket), addr)),
+                Err(e) => Err((e.kind().into(), e)),
+            }
+        } else {
+            match happy_eyeballs::connect(addrs).await {
+                Ok((stream, addr)) => Ok((Remote::Tcp(stream), addr)),
+                Err(e) => Err((e.kind().into(), e)),
+            }
+        }
+    };
+
+    let reason = match time::timeout(server.connect_timeout, connect).await {
+        Ok(Ok((remote, addr))) => {
+            handle.notify(Event::Connected {
+                request_id,
+                addr,
+                remote,
+            });
+
+            return;
+        }
+        Ok(Err((reason, e))) => {
+            log::debug!("{remote_addr} failed to connect to {host}:{port}: {e}");
+            reason
+        }
+        Err(_) => {
+            log::debug!("{remote_addr} timed out connecting to {host}:{port}");
+            ConnectFailReason::TimedOut
+        }
+    };
+
+    let failed = CDataTypeByte::ConnectFailed { request_id, reason };
+
+    if let Err(e) = handle.send(failed).await {
+        log::debug!("Failed to relay data to {remote_addr}: {e}");
+    }
+}
+
+async fn connect_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let unspecified = match addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+
+    let socket = UdpSocket::bind((unspecified, 0)).await?;
+    socket.connect(addr).await?;
+
+    Ok(socket)
+}
+
+/// Writes what the client sent to the remote host, then shuts it down once the
+/// client did.
+async fn write_tcp(
+    mut stream: OwnedWriteHalf,
+    mut writes: mpsc::Receiver<Vec<u8>>,
+    connection_id: u16,
+    handle: RelayHandle,
+) {
+    while let Some(data) = writes.recv().await {
+        if let Err(e) = stream.write_all(&data).await {
+            log::debug!(
+                "Connection {connection_id} of {} failed: {e}",
+                handle.remote_addr
+            );
+
+            // Once the client shut down, the connection may already be
+            // gone and its id taken by another one. The reader reports
+            // failures of its own.
+            if !writes.is_closed() {
+                handle.notify(Event::WriteFailed(connection_id, e.kind()));
+            }
+
+            return;
+        }
+    }
+
+    // Fails only if the connection is already gone, which the reader reports
+    // by itself.
+    let _ = stream.shutdown().await;
+}
+
+/// Sends everything the remote host writes to the client, followed by a
+/// shutdown on EOF or a reset if the connection fails. Nothing is read while
+/// the client has paused the connection.
+async fn relay_tcp(
+    mut stream: OwnedReadHalf,
+    connection_id: u16,
+    handle: RelayHandle,
+    closed: CancellationToken,
+    mut paused: watch::Receiver<bool>,
+) {
+    let remote_addr = handle.remote_addr;
+    let mut buf = vec![0; RELAY_BUF_SIZE];
+
+    let (data_type, event) = loop {
+        // Fails only once the connection is dropped, which also cancels
+        // `closed`.
+        tokio::select! {
+            _ = paused.wait_for(|paused| !paused) => {}
+            _ = closed.cancelled() => return,
+        }
+
+        let res = tokio::select! {
+            res = stream.read(&mut buf) => res,
+            _ = closed.cancelled() => return,
+        };
+
+        let n = match res {
+            Ok(0) => {
+                break (
+                    CDataTypeByte::Shutdown { connection_id },
+                    Event::Eof(connection_id),
+                );
+            }
+            Ok(n) => n,
+            Err(e) => {
+                log::debug!("Connection {connection_id} of {remote_addr} failed: {e}");
+
+                break (
+                    CDataTypeByte::Reset {
+                        connection_id,
+                        reason: e.kind().into(),
+                    },
+                    Event::Reset(connection_id),
+                );
+            }
+        };
+
+        let data = CDataTypeByte::Process {
+            connection_id,
+            data: &buf[..n],
+        };
+
+        match handle.send_while_open(data, &closed).await {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => {
+                log::debug!("Failed to relay data to {remote_addr}: {e}");
+                return;
+            }
+        }
+    };
+
+    match handle.send_while_open(data_type, &closed).await {
+        Ok(true) => handle.notify(event),
+        Ok(false) => {}
+        Err(e) => log::debug!("Failed to relay data to {remote_addr}: {e}"),
+    }
+}
+
+/// Sends every datagram from the target to the client as a separate packet,
+/// until the association stays idle for longer than `idle_timeout`.
+async fn relay_udp(
+    association: Arc<UdpAssociation>,
+    connection_id: u16,
+    idle_timeout: Duration,
+    handle: RelayHandle,
+    closed: CancellationToken,
+) {
+    let remote_addr = handle.remote_addr;
+    let mut buf = vec![0; UDP_BUF_SIZE];
+
+    let reason = loop {
+        let deadline = association.idle_deadline(idle_timeout);
+
+        let n = tokio::select! {
+            res = association.socket.recv(&mut buf) => match res {
+                Ok(n) => n,
+                Err(e) => {
+                    log::debug!("Connection {connection_id} of {remote_addr} failed: {e}");
+                    break e.kind().into();
+                }
+            },
+            _ = time::sleep_until(deadline) => {
+                // Data sent by the client could have moved the deadline.
+                if association.idle_deadline(idle_timeout) <= Instant::now() {
+                    log::debug!("Connection {connection_id} of {remote_addr} expired");
+                    break ResetReason::Idle;
+                }
+
+                continue;
+            }
+            _ = closed.cancelled() => return,
+        };
+
+        association.touch();
+
+        let data = CDataTypeByte::Process {
+            connection_id,
+            data: &buf[..n],
+        };
+
+        match handle.send_while_open(data, &closed).await {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(e) => {
+                log::debug!("Failed to relay data to {remote_addr}: {e}");
+                return;
+            }
+        }
+    };
+
+    let reset = CDataTypeByte::Reset {
+        connection_id,
+        reason,
+    };
+
+    match handle.send_while_open(reset, &closed).await {
+        Ok(true) => handle.notify(Event::Reset(connection_id)),
+        Ok(false) => {}
+        Err(e) => log::debug!("Failed to relay data to {remote_addr}: {e}"),
+    }
+}
+
+/// Whether the client closed the connection between packets.
+fn is_eof(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == ErrorKind::UnexpectedEof)
+}
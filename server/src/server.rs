@@ -1,53 +1,383 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::Result;
-use rsa::{RsaPrivateKey, rand_core::OsRng, traits::PublicKeyParts};
-use tokio::net::TcpListener;
-use uuid::Uuid;
+use anyhow::{Context, Result, ensure};
+use protocol::{CompressionThreshold, MAX_PACKET_SIZE, key_fingerprint};
+use rsa::RsaPrivateKey;
+use tokio::{net::TcpListener, sync::Semaphore, task::JoinSet, time};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
-use crate::{connection::Client, ping::ServerListPing};
+use crate::{
+    config::{self, Config, Limits, Shutdown, Timeouts, User},
+    connection::Client,
+    handler::{Handler, Whitelist},
+    key,
+    limits::RateLimiter,
+    ping::ServerListPing,
+};
 
-pub struct Server {
+/// How long accepting waits after the first failure, doubled on every
+/// further one.
+const MIN_ACCEPT_DELAY: Duration = Duration::from_millis(50);
+const MAX_ACCEPT_DELAY: Duration = Duration::from_secs(5);
+/// How long clients get to leave once their connections were closed at the
+/// end of draining.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Server<H = Whitelist> {
     pub private_key: RsaPrivateKey,
     pub public_key: Box<[u8]>,
     pub server_list_ping: ServerListPing,
-    pub login_data: HashMap<String, (Uuid, Uuid)>,
+    /// Decides who may log in and what they may do.
+    pub handler: H,
+    pub compression_threshold: CompressionThreshold,
+    /// How long connecting to a remote host may take.
+    pub connect_timeout: Duration,
+    /// How long a UDP association may stay without traffic before it is closed.
+    pub udp_idle_timeout: Duration,
+    /// How long a new client may take to send its handshake.
+    pub handshake_timeout: Duration,
+    /// How long answering a status request may take.
+    pub status_timeout: Duration,
+    /// How long logging in may take.
+    pub login_timeout: Duration,
+    /// Where listeners requested by clients are opened.
+    pub reverse_listen_ip: IpAddr,
+    /// How long logged in clients may keep their open connections after a
+    /// shutdown.
+    pub drain_timeout: Duration,
+    /// Shown to clients when they are disconnected by a shutdown.
+    pub shutdown_message: String,
+
+    /// Cancelled when the server stops accepting clients.
+    pub(crate) shutting_down: CancellationToken,
+    /// Cancelled when the clients which are still connected have to go.
+    pub(crate) drain_expired: CancellationToken,
+    clients: TaskTracker,
+
+    pub(crate) status_limiter: RateLimiter,
+    pub(crate) login_limiter: RateLimiter,
+    /// A permit for every client which hasn't logged in yet.
+    unauthenticated: Arc<Semaphore>,
+}
+
+/// Configures a [`Server`] from code instead of a config file. Anything not
+/// set keeps the default of the config file.
+pub struct Builder<H = Whitelist> {
+    private_key: RsaPrivateKey,
+    handler: H,
+    server_list_ping: ServerListPing,
+    compression_threshold: i32,
+    timeouts: Timeouts,
+    reverse_listen_ip: IpAddr,
+    shutdown: Shutdown,
+    limits: Limits,
 }
 
 impl Server {
-    pub fn new() -> Result<Self> {
-        let private_key = RsaPrivateKey::new(&mut OsRng, 1024)?;
-        let public_key = rsa_der::public_key_to_der(
-            &private_key.n().to_bytes_be(),
-            &private_key.e().to_bytes_be(),
-        )
-        .into_boxed_slice();
-
-        Ok(Self {
+    /// Starts configuring a server with `private_key` and no users.
+    pub fn builder(private_key: RsaPrivateKey) -> Builder {
+        Builder {
             private_key,
-            public_key,
+            handler: Whitelist::default(),
             server_list_ping: ServerListPing::default(),
-            login_data: HashMap::default(),
-        })
+            compression_threshold: config::default_compression_threshold(),
+            timeouts: Timeouts::default(),
+            reverse_listen_ip: config::default_reverse_listen_ip(),
+            shutdown: Shutdown::default(),
+            limits: Limits::default(),
+        }
+    }
+
+    pub fn new(config: &Config) -> Result<Self> {
+        let private_key = key::load_or_generate(&config.key_path, config.key_size)?;
+
+        Self::builder(private_key)
+            .users(config.users.iter().cloned())
+            .status(ServerListPing::new(&config.status)?)
+            .compression_threshold(config.compression_threshold)
+            .connect_timeout(config.timeouts.connect)
+            .udp_idle_timeout(config.timeouts.udp_idle)
+            .handshake_timeout(config.timeouts.handshake)
+            .status_timeout(config.timeouts.status)
+            .login_timeout(config.timeouts.login)
+            .limits(config.limits.clone())
+            .reverse_listen_ip(config.reverse_listen_ip)
+            .drain_timeout(config.shutdown.drain_timeout)
+            .shutdown_message(config.shutdown.message.clone())
+            .build()
     }
+}
+
+impl<H: Handler> Server<H> {
+    /// Accepts clients on `addrs` until [`shutdown`](Self::shutdown) is
+    /// called, then waits for them to [`drain`](Self::drain).
+    pub async fn start(self: Arc<Self>, addrs: &[SocketAddr]) -> Result<()> {
+        let mut listeners = JoinSet::new();
+
+        // Bind everything first, so a typo in the config fails right away.
+        for addr in addrs {
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("failed to listen on {addr}"))?;
+
+            log::info!("Server started on {addr}");
+
+            listeners.spawn(self.clone().serve(listener));
+        }
+
+        while let Some(res) = listeners.join_next().await {
+            res?;
+        }
+
+        self.drain().await;
+
+        Ok(())
+    }
+
+    /// Stops accepting clients. Those still logging in are disconnected, while
+    /// logged in ones may keep their open connections for a while but can't
+    /// open new ones.
+    pub fn shutdown(&self) {
+        self.shutting_down.cancel();
+    }
+
+    /// Waits for the clients to leave after a shutdown. Sessions which still
+    /// have open connections after `drain_timeout` get them closed.
+    pub async fn drain(&self) {
+        self.shutting_down.cancelled().await;
+        self.clients.close();
+
+        if !self.clients.is_empty() {
+            log::info!(
+                "Waiting up to {:?} for {} client(s) to leave",
+                self.drain_timeout,
+                self.clients.len()
+            );
+        }
+
+        if time::timeout(self.drain_timeout, self.clients.wait())
+            .await
+            .is_ok()
+        {
+            return;
+        }
+
+        log::warn!(
+            "Closing the connections of {} client(s) which didn't leave in time",
+            self.clients.len()
+        );
+
+        self.drain_expired.cancel();
+
+        if time::timeout(CLOSE_TIMEOUT, self.clients.wait())
+            .await
+            .is_err()
+        {
+            log::warn!("{} client(s) didn't leave", self.clients.len());
+        }
+    }
+
+    /// Accepts clients on a listener bound elsewhere, until the server shuts
+    /// down. Failing to accept doesn't stop it, as that is usually a passing
+    /// shortage of file descriptors or memory.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        let mut delay = MIN_ACCEPT_DELAY;
+
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = self.shutting_down.cancelled() => return,
+            };
+
+            let (stream, remote_addr) = match accepted {
+                Ok(accepted) => accepted,
+                // The client gave up before it was accepted.
+                Err(e) if e.kind() == ErrorKind::ConnectionAborted => continue,
+                Err(e) => {
+                    log::warn!("Failed to accept a client, retrying in {delay:?}: {e}");
+                    time::sleep(delay).await;
+
+                    delay = (delay * 2).min(MAX_ACCEPT_DELAY);
+                    continue;
+                }
+            };
 
-    pub async fn start(self: Arc<Self>, addr: &str) -> Result<()> {
-        let listener = TcpListener::bind(addr).await?;
+            delay = MIN_ACCEPT_DELAY;
 
-        log::info!("Server started on {addr}");
+            // Checked before anything is read, as reading is what a flood
+            // would keep the server busy with.
+            let Ok(permit) = self.unauthenticated.clone().try_acquire_owned() else {
+                log::debug!("Dropped {remote_addr}, too many clients are logging in");
+                continue;
+            };
 
-        while let Ok((stream, remote_addr)) = listener.accept().await {
             let server = self.clone();
 
-            tokio::spawn(async move {
-                Client::new(stream, remote_addr, server)
-                    .unwrap()
-                    .handle()
-                    .await
-                    .unwrap();
+            self.clients.spawn(async move {
+                match Client::new(stream, remote_addr, server, permit) {
+                    Ok(client) => client.handle().await,
+                    Err(e) => log::info!("Connection with {remote_addr} failed: {e}"),
+                }
             });
         }
+    }
+}
 
-        Ok(())
+impl Builder {
+    /// Lets in exactly these users, replacing any handler set before.
+    pub fn users(self, users: impl IntoIterator<Item = User>) -> Self {
+        self.handler(Whitelist::new(users))
+    }
+}
+
+impl<H: Handler> Builder<H> {
+    /// Replaces the policy for logins, connects, binds and status requests.
+    pub fn handler<T: Handler>(self, handler: T) -> Builder<T> {
+        Builder {
+            private_key: self.private_key,
+            handler,
+            server_list_ping: self.server_list_ping,
+            compression_threshold: self.compression_threshold,
+            timeouts: self.timeouts,
+            reverse_listen_ip: self.reverse_listen_ip,
+            shutdown: self.shutdown,
+            limits: self.limits,
+        }
+    }
+
+    /// What the multiplayer server list shows, unless the handler says
+    /// otherwise.
+    pub fn status(mut self, server_list_ping: ServerListPing) -> Self {
+        self.server_list_ping = server_list_ping;
+        self
+    }
+
+    /// Packets at least this large are compressed. Negative values disable
+    /// compression.
+    pub fn compression_threshold(mut self, threshold: i32) -> Self {
+        self.compression_threshold = threshold;
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.connect = timeout;
+        self
+    }
+
+    pub fn udp_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.udp_idle = timeout;
+        self
+    }
+
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.handshake = timeout;
+        self
+    }
+
+    pub fn status_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.status = timeout;
+        self
+    }
+
+    pub fn login_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.login = timeout;
+        self
+    }
+
+    /// Rate limits per network and the cap on clients which haven't logged
+    /// in yet.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn reverse_listen_ip(mut self, ip: IpAddr) -> Self {
+        self.reverse_listen_ip = ip;
+        self
+    }
+
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown.drain_timeout = timeout;
+        self
+    }
+
+    pub fn shutdown_message(mut self, message: impl Into<String>) -> Self {
+        self.shutdown.message = message.into();
+        self
+    }
+
+    pub fn build(self) -> Result<Server<H>> {
+        ensure!(
+            self.compression_threshold < MAX_PACKET_SIZE,
+            "compression threshold must be less than {MAX_PACKET_SIZE}"
+        );
+        let timeouts = &self.timeouts;
+        ensure!(
+            [
+                timeouts.connect,
+                timeouts.udp_idle,
+                timeouts.handshake,
+                timeouts.status,
+                timeouts.login,
+            ]
+            .iter()
+            .all(|timeout| !timeout.is_zero()),
+            "timeouts must be greater than zero"
+        );
+
+        let limits = &self.limits;
+        ensure!(
+            limits.max_unauthenticated > 0,
+            "at least one unauthenticated client must be allowed"
+        );
+        ensure!(
+            limits.ipv4_prefix <= 32 && limits.ipv6_prefix <= 128,
+            "rate limit prefix lengths must fit the address"
+        );
+        ensure!(
+            limits.status.burst > 0 && limits.login.burst > 0,
+            "rate limit bursts must be greater than zero"
+        );
+
+        let public_key = key::public_key_der(&self.private_key)?;
+
+        log::info!("Server key fingerprint: {}", key_fingerprint(&public_key));
+
+        Ok(Server {
+            private_key: self.private_key,
+            public_key,
+            server_list_ping: self.server_list_ping,
+            handler: self.handler,
+            compression_threshold: CompressionThreshold(self.compression_threshold),
+            connect_timeout: self.timeouts.connect,
+            udp_idle_timeout: self.timeouts.udp_idle,
+            handshake_timeout: self.timeouts.handshake,
+            status_timeout: self.timeouts.status,
+            login_timeout: self.timeouts.login,
+            reverse_listen_ip: self.reverse_listen_ip,
+            drain_timeout: self.shutdown.drain_timeout,
+            shutdown_message: self.shutdown.message,
+
+            shutting_down: CancellationToken::new(),
+            drain_expired: CancellationToken::new(),
+            clients: TaskTracker::new(),
+
+            status_limiter: RateLimiter::new(
+                self.limits.status,
+                self.limits.ipv4_prefix,
+                self.limits.ipv6_prefix,
+            ),
+            login_limiter: RateLimiter::new(
+                self.limits.login,
+                self.limits.ipv4_prefix,
+                self.limits.ipv6_prefix,
+            ),
+            unauthenticated: Arc::new(Semaphore::new(self.limits.max_unauthenticated)),
+        })
     }
 }